@@ -10,7 +10,7 @@ pub trait BrassEngine: Engine {
     fn context_from_path(self, wasm_path: &Path) -> anyhow::Result<RunnerContext<Self>>;
 }
 
-use gwr_backend::Flags;
+use gwr_backend::{Flags, Limits};
 pub use runner::run;
 
 #[derive(Debug, Clone)]
@@ -31,6 +31,17 @@ impl BrassBackend {
         wasm_path: &Path,
         args: &[String],
     ) -> anyhow::Result<()> {
-        run(engine, wasm_path, flags.skip_confirmation, args)
+        if flags.resume.is_some() {
+            // Brass Golem computes the task as a whole and keeps no per-subtask results.
+            anyhow::bail!("--resume is not supported by the Brass backend");
+        }
+        run(
+            engine,
+            wasm_path,
+            &Limits::from_flags(flags),
+            flags.skip_confirmation,
+            flags.output_dir.as_deref(),
+            args,
+        )
     }
 }
@@ -7,7 +7,9 @@ use {
     crate::{config::GolemConfig, task::TaskBuilder},
     gwasm_api::prelude::{compute, ComputedTask, GWasmBinary, ProgressUpdate},
     gwr_backend::{
-        config_path, dispatcher::TaskDef, for_spwasm, for_wasmtime, rt, run_local_code, WorkDir,
+        check_app, config_path,
+        dispatcher::{TaskDef, TaskFile},
+        for_spwasm, for_wasmtime, rt, run_local_code, run_merge_code, Limits, WorkDir,
     },
     indicatif::ProgressBar,
     promptly::prompt_default,
@@ -86,24 +88,29 @@ for_wasmtime! {
 pub fn run<E: super::BrassEngine>(
     engine: E,
     wasm_path: &Path,
+    limits: &Limits,
     skip_confirmation: bool,
+    output_dir: Option<&Path>,
     args: &[String],
 ) -> anyhow::Result<()> {
-    let mut context = engine.context_from_path(wasm_path)?;
+    let mut context = engine.clone().context_from_path(wasm_path)?;
+    context
+        .workdir
+        .set_app_protocol(check_app(engine, limits, wasm_path)?);
 
     if !skip_confirmation && !has_user_confirmed(&wasm_path) {
         anyhow::bail!("Task creation aborted.");
     }
 
-    context.split(args)?;
+    context.split(limits, args)?;
     let (computed_task, subtask_order) = context.execute()?;
-    context.merge(args, computed_task, subtask_order)?;
+    context.merge(limits, output_dir, args, computed_task, subtask_order)?;
     log::info!("Task computed!");
     Ok(())
 }
 
 impl<E: rt::Engine> RunnerContext<E> {
-    fn split(&mut self, args: &[String]) -> anyhow::Result<()> {
+    fn split(&mut self, limits: &Limits, args: &[String]) -> anyhow::Result<()> {
         let output_path = self.workdir.split_output()?;
         let mut split_args = Vec::new();
         split_args.push("split".to_owned());
@@ -114,6 +121,7 @@ impl<E: rt::Engine> RunnerContext<E> {
 
         run_local_code(
             self.engine_ref.clone(),
+            limits,
             &self.wasm_path,
             &output_path,
             split_args,
@@ -161,6 +169,8 @@ impl<E: rt::Engine> RunnerContext<E> {
 
     fn merge(
         &mut self,
+        limits: &Limits,
+        output_dir: Option<&Path>,
         args: &[String],
         task: ComputedTask,
         subtask_order: Vec<String>,
@@ -181,7 +191,10 @@ impl<E: rt::Engine> RunnerContext<E> {
 
             for (path, reader) in subtask.data {
                 if path.ends_with("task.json") {
-                    let output_data: TaskDef = serde_json::from_reader(reader)?;
+                    let output_data = serde_json::from_reader::<_, TaskFile<TaskDef>>(reader)?.data;
+                    if !output_data.spawned().is_empty() {
+                        anyhow::bail!("spawning subtasks is not supported by the Brass backend");
+                    }
                     output_agg.push(output_data.rebase_to(&output_path, &merge_path)?);
                 }
             }
@@ -192,7 +205,7 @@ impl<E: rt::Engine> RunnerContext<E> {
                 .create_new(true)
                 .write(true)
                 .open(merge_path.join("tasks_output.json"))?,
-            &output_agg,
+            &self.workdir.task_file(&output_agg),
         )?;
 
         let mut merge_args = Vec::new();
@@ -204,12 +217,17 @@ impl<E: rt::Engine> RunnerContext<E> {
 
         log::debug!("merge args: {:?}", merge_args);
 
-        run_local_code(
+        run_merge_code(
             self.engine_ref.clone(),
+            limits,
             &self.wasm_path,
-            merge_path.parent().unwrap(),
+            &merge_path,
             merge_args,
+            output_dir,
         )?;
+        if self.workdir.next_round()? {
+            anyhow::bail!("multi-round apps are not supported by the Brass backend");
+        }
 
         Ok(())
     }
@@ -1,5 +1,4 @@
-use gwr_backend::dispatcher::TaskDef;
-use gwr_backend::WorkDir;
+use gwr_backend::{load_split, place_blob, WorkDir};
 use {
     gwasm_api::prelude::{GWasmBinary, Options, Subtask, Task, Timeout},
     std::{
@@ -89,26 +88,31 @@ impl<'a> TaskBuilder<'a> {
 
         let split_dir = self.workdir.split_output()?;
         let merge_dir = self.workdir.merge_path()?;
-        let tasks_path = split_dir.join("tasks.json");
-        let tasks: Vec<TaskDef> =
-            serde_json::from_reader(OpenOptions::new().read(true).open(tasks_path)?)?;
+        let tasks = load_split(&split_dir)?;
 
         let mut input_agg = Vec::new();
 
         let subtask_order: anyhow::Result<Vec<String>> = tasks
             .into_iter()
-            .map(|task| {
-                let subtask_dir = self.workdir.new_task()?;
+            .enumerate()
+            .map(|(idx, task)| {
+                if task.blob_dirs().next().is_some() || task.output_dirs().next().is_some() {
+                    anyhow::bail!("blob dirs are not supported by the Brass backend");
+                }
+                let subtask_dir = self.workdir.task_path(idx)?;
 
                 // Output does not have its separate dir since Brass does not expect subdirectories
                 // in its output dir
                 let subtask_input_path = subtask_dir.join("in");
                 std::fs::create_dir(&subtask_input_path)?;
 
-                for blob_path in task.blobs() {
-                    std::fs::rename(
-                        &split_dir.join(blob_path),
-                        subtask_input_path.join(blob_path),
+                // Brass uploads every subtask dir as a whole, so shared blobs are
+                // only kept from being moved away from the other subtasks.
+                for blob in task.blob_refs() {
+                    place_blob(
+                        &split_dir.join(&blob.path),
+                        &subtask_input_path.join(&blob.path),
+                        blob.shared,
                     )?;
                 }
 
@@ -119,7 +123,7 @@ impl<'a> TaskBuilder<'a> {
                             .write(true)
                             .open(subtask_input_path.join("task.json"))?,
                     ),
-                    &task,
+                    &self.workdir.task_file(&task),
                 )?;
 
                 let mut subtask = Subtask::new();
@@ -139,6 +143,7 @@ impl<'a> TaskBuilder<'a> {
                     "exec".to_owned(),
                     "/in/task.json".to_owned(),
                     "/task.json".to_owned(),
+                    idx.to_string(),
                 ];
 
                 let subtask_name = subtask_dir
@@ -161,7 +166,7 @@ impl<'a> TaskBuilder<'a> {
                 .create_new(true)
                 .write(true)
                 .open(merge_dir.join("tasks_input.json"))?,
-            &input_agg,
+            &self.workdir.task_file(&input_agg),
         )?;
 
         Ok((
@@ -0,0 +1,52 @@
+//! Blob dirs packed into single files, for transports that only move files.
+use anyhow::Result as Fallible;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Location of the archive of the blob dir at `dir`, kept next to it.
+pub fn archive_path(dir: &Path) -> PathBuf {
+    let mut name = dir.file_name().unwrap_or_default().to_os_string();
+    name.push(".tar");
+    dir.with_file_name(name)
+}
+
+/// Packs the blob dir at `dir` into a tar archive and returns the archive path.
+pub fn pack_dir(dir: &Path) -> Fallible<PathBuf> {
+    let tar_path = archive_path(dir);
+    let mut builder = tar::Builder::new(fs::File::create(&tar_path)?);
+    builder.append_dir_all(".", dir)?;
+    builder.finish()?;
+    Ok(tar_path)
+}
+
+/// Unpacks the archive at `tar_path` into the blob dir at `dir`, then removes the archive.
+pub fn unpack_dir(tar_path: &Path, dir: &Path) -> Fallible<()> {
+    fs::create_dir_all(dir)?;
+    tar::Archive::new(fs::File::open(tar_path)?).unpack(dir)?;
+    fs::remove_file(tar_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pack_unpack() {
+        let base = std::env::temp_dir().join("gwr-backend-test_pack_unpack");
+        let _ = fs::remove_dir_all(&base);
+        let dir = base.join("tiles.dir");
+        fs::create_dir_all(dir.join("row")).unwrap();
+        fs::write(dir.join("a.bin"), "a").unwrap();
+        fs::write(dir.join("row").join("b.bin"), "b").unwrap();
+
+        let tar_path = pack_dir(&dir).unwrap();
+        assert_eq!(tar_path, base.join("tiles.dir.tar"));
+        let out = base.join("out.dir");
+        unpack_dir(&tar_path, &out).unwrap();
+        assert!(!tar_path.exists());
+        assert_eq!(fs::read(out.join("a.bin")).unwrap(), b"a");
+        assert_eq!(fs::read(out.join("row").join("b.bin")).unwrap(), b"b");
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
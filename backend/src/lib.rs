@@ -1,14 +1,24 @@
+pub mod archive;
+mod limits;
 mod local_runner;
+mod progress;
+mod split_stream;
+mod validate;
 mod workdir;
 
 use app_dirs::AppInfo;
 pub use gwasm_dispatcher as dispatcher;
 pub use gwr_runtime_api as rt;
 use humantime::Duration;
-pub use local_runner::{run_local_code, run_on_local};
+pub use limits::Limits;
+pub use local_runner::{
+    query_app_info, run_local_code, run_merge_code, run_on_local, run_split_streaming,
+};
+pub use progress::subtasks_bar;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
-pub use workdir::WorkDir;
+pub use validate::{load_split, SplitCheck};
+pub use workdir::{place_blob, task_log_paths, WorkDir};
 
 #[cfg(feature = "spwasm")]
 #[macro_export]
@@ -79,29 +89,212 @@ pub struct Flags {
     /// Skip confirmation dialogs
     #[structopt(short = "y", long = "assume-yes")]
     pub skip_confirmation: bool,
-    /// Set timeout for all tasks (Wasi mode only).
-    #[structopt(long, default_value = "3h")]
-    pub timeout: Duration,
+    /// Set timeout for all tasks. Runs have no deadline unless it is given.
+    #[structopt(long)]
+    pub timeout: Option<Duration>,
+    /// Set timeout for a single subtask.
+    #[structopt(long = "subtask-timeout")]
+    pub subtask_timeout: Option<Duration>,
+    /// Number of subtasks to run concurrently (Local backend only).
+    /// Defaults to the number of available cores.
+    #[structopt(long, short = "j")]
+    pub workers: Option<usize>,
+    /// Resume the run with the given id from its last round, re-running only unfinished subtasks.
+    #[structopt(long, value_name = "run-id")]
+    pub resume: Option<String>,
+    /// Maximum guest linear memory of a single subtask, in MiB.
+    #[structopt(long = "max-memory", value_name = "MiB")]
+    pub max_memory: Option<usize>,
+    /// Maximum number of elements in a single table of a subtask.
+    #[structopt(long = "max-table-elements", value_name = "count")]
+    pub max_table_elements: Option<usize>,
+    /// Maximum number of module instances of a single subtask.
+    #[structopt(long = "max-instances", value_name = "count")]
+    pub max_instances: Option<usize>,
+    /// Meter fuel consumed by each subtask (wasmtime runtime only).
+    #[structopt(long)]
+    pub fuel: bool,
+    /// Compile the Wasm App on every run instead of reusing cached compiled code.
+    #[structopt(long = "no-module-cache")]
+    pub no_module_cache: bool,
+    /// Directory to save result files declared by the Wasm App on merge.
+    /// When set, merge gets read-only access to the host filesystem.
+    #[structopt(long = "output-dir", value_name = "path", parse(from_os_str))]
+    pub output_dir: Option<PathBuf>,
+}
+
+impl Flags {
+    pub fn engine_config(&self) -> rt::EngineConfig {
+        let cache_dir = if self.no_module_cache {
+            None
+        } else {
+            app_dirs::app_dir(app_dirs::AppDataType::UserCache, &GWASM_APP_INFO, "modules")
+                .map_err(|e| log::warn!("module cache disabled: {}", e))
+                .ok()
+        };
+        rt::EngineConfig {
+            fuel_metering: self.fuel,
+            cache_dir,
+        }
+    }
+}
+
+/// Returns the dispatcher protocol of the Wasm App, for `WorkDir::set_app_protocol`.
+///
+/// Apps that cannot describe themselves are run as protocol 1 apps, with task
+/// files in the format they expect. Only apps newer than the runner are rejected.
+pub fn check_app<E: rt::Engine>(
+    engine: E,
+    limits: &Limits,
+    wasm_path: &Path,
+) -> anyhow::Result<u32> {
+    let runner_protocol = dispatcher::dispatcher::PROTOCOL_VERSION;
+    let info = match query_app_info(engine, limits, wasm_path)? {
+        Some(info) => info,
+        None => {
+            log::warn!(
+                "{} does not support the info command, running it as a protocol 1 app",
+                wasm_path.display()
+            );
+            return Ok(1);
+        }
+    };
+    let protocol = match info["protocol"].as_u64() {
+        Some(protocol) if protocol <= u64::from(runner_protocol) => protocol as u32,
+        _ => anyhow::bail!(
+            "{} uses dispatcher protocol {}, runner supports up to {}",
+            wasm_path.display(),
+            info["protocol"],
+            runner_protocol
+        ),
+    };
+    if protocol < runner_protocol {
+        log::info!(
+            "running {} as a protocol {} app",
+            wasm_path.display(),
+            protocol
+        );
+    }
+    if let (Some(name), Some(version)) = (
+        info["app"]["name"].as_str(),
+        info["app"]["version"].as_str(),
+    ) {
+        log::info!("running {} {}", name, version);
+    }
+    Ok(protocol)
+}
+
+/// Runs only the split step of the Wasm App and checks its output.
+pub fn validate_tasks<E: rt::Engine>(
+    engine: E,
+    flags: &Flags,
+    wasm_path: &Path,
+    args: &[String],
+) -> anyhow::Result<()> {
+    let limits = Limits::from_flags(flags);
+    check_app(engine.clone(), &limits, wasm_path)?;
+    let mut w = WorkDir::for_run("validate", None)?;
+    let split_path = w.split_output()?;
+    let mut split_args = vec!["split".to_owned(), "/task_dir/".to_owned()];
+    split_args.extend(args.iter().cloned());
+    run_local_code(engine, &limits, wasm_path, &split_path, split_args)?;
+
+    let tasks = load_split(&split_path)?;
+    log::info!(
+        "{} work items are valid, split output in {}",
+        tasks.len(),
+        split_path.display()
+    );
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
-pub struct LocalBackend {}
+pub struct LocalBackend {
+    workers: Option<usize>,
+}
 
 impl LocalBackend {
     pub fn parse_url(url: &str) -> anyhow::Result<Option<Self>> {
-        Ok(match url {
-            "L" | "Local" | "local" => Some(LocalBackend {}),
-            _ => None,
-        })
+        let (name, query) = match url.find('?') {
+            Some(pos) => (&url[..pos], &url[pos + 1..]),
+            None => (url, ""),
+        };
+        match name {
+            "L" | "Local" | "local" => (),
+            _ => return Ok(None),
+        }
+
+        let mut workers = None;
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = match pair.find('=') {
+                Some(pos) => (&pair[..pos], &pair[pos + 1..]),
+                None => (pair, ""),
+            };
+            match key {
+                "workers" => {
+                    let n: usize = value
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid workers value: {:?}", value))?;
+                    if n == 0 {
+                        anyhow::bail!("workers must be greater than 0");
+                    }
+                    workers = Some(n)
+                }
+                _ => log::warn!("unknown url key: {}", key),
+            }
+        }
+        Ok(Some(LocalBackend { workers }))
     }
 
-    pub fn run<E: rt::Engine>(
+    pub fn run<E: rt::Engine + Send + Sync>(
         &self,
         engine: E,
-        _flags: &Flags,
+        flags: &Flags,
         wasm_path: &Path,
         args: &[String],
     ) -> anyhow::Result<()> {
-        run_on_local(engine, wasm_path, args)
+        let workers = self
+            .workers
+            .or(flags.workers)
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            })
+            .max(1);
+        let limits = Limits::from_flags(flags);
+        let mut w = WorkDir::for_run("local", flags.resume.as_deref())?;
+        w.set_app_protocol(check_app(engine.clone(), &limits, wasm_path)?);
+        run_on_local(
+            engine,
+            &limits,
+            w,
+            wasm_path,
+            workers,
+            args,
+            flags.output_dir.as_deref(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_local_url() {
+        assert_eq!(
+            LocalBackend::parse_url("local").unwrap().unwrap().workers,
+            None
+        );
+        assert_eq!(
+            LocalBackend::parse_url("local?workers=8")
+                .unwrap()
+                .unwrap()
+                .workers,
+            Some(8)
+        );
+        assert!(LocalBackend::parse_url("local?workers=0").is_err());
+        assert!(LocalBackend::parse_url("yagna").unwrap().is_none());
     }
 }
@@ -0,0 +1,123 @@
+use crate::rt::ResourceLimits;
+use crate::Flags;
+use std::time::{Duration, Instant};
+
+/// Execution limits applied to every sandbox started during a run.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    deadline: Option<Instant>,
+    subtask_timeout: Option<Duration>,
+    resources: ResourceLimits,
+}
+
+impl Limits {
+    pub fn new(timeout: Option<Duration>, subtask_timeout: Option<Duration>) -> Self {
+        Limits {
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+            subtask_timeout,
+            resources: ResourceLimits::default(),
+        }
+    }
+
+    pub fn with_resources(mut self, resources: ResourceLimits) -> Self {
+        self.resources = resources;
+        self
+    }
+
+    /// Limits for a run starting now.
+    pub fn from_flags(flags: &Flags) -> Self {
+        Limits::new(
+            flags.timeout.map(Into::into),
+            flags.subtask_timeout.map(Into::into),
+        )
+        .with_resources(ResourceLimits {
+            max_memory: flags.max_memory.map(|mib| mib << 20),
+            max_table_elements: flags.max_table_elements,
+            max_instances: flags.max_instances,
+        })
+    }
+
+    /// Guest resource caps of every sandbox.
+    pub fn resources(&self) -> &ResourceLimits {
+        &self.resources
+    }
+
+    /// Deadline of the whole run, runs without `--timeout` have none.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Deadline for a subtask started now.
+    pub fn subtask_deadline(&self) -> Option<Instant> {
+        let subtask_deadline = self.subtask_timeout.map(|timeout| Instant::now() + timeout);
+        match (self.deadline, subtask_deadline) {
+            (Some(deadline), Some(subtask_deadline)) => Some(deadline.min(subtask_deadline)),
+            (deadline, subtask_deadline) => deadline.or(subtask_deadline),
+        }
+    }
+
+    /// Fails once the deadline of the whole run has passed.
+    pub fn check_deadline(&self) -> anyhow::Result<()> {
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() > deadline)
+        {
+            anyhow::bail!("run timed out");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subtask_deadline() {
+        let limits = Limits::new(Some(Duration::from_secs(60)), None);
+        assert_eq!(limits.subtask_deadline(), limits.deadline());
+
+        let limits = Limits::new(Some(Duration::from_secs(60)), Some(Duration::from_secs(10)));
+        assert!(limits.subtask_deadline() < limits.deadline());
+
+        let limits = Limits::new(Some(Duration::from_secs(10)), Some(Duration::from_secs(60)));
+        assert_eq!(limits.subtask_deadline(), limits.deadline());
+        assert!(limits.check_deadline().is_ok());
+
+        let limits = Limits::new(None, Some(Duration::from_secs(10)));
+        assert!(limits.deadline().is_none());
+        assert!(limits.subtask_deadline().is_some());
+        assert!(Limits::new(None, None).subtask_deadline().is_none());
+    }
+
+    #[test]
+    fn test_from_flags() {
+        use structopt::StructOpt;
+
+        let flags = Flags::from_iter(&[
+            "gwasm-runner",
+            "--max-memory",
+            "2",
+            "--max-table-elements",
+            "10",
+            "--max-instances",
+            "1",
+            "--subtask-timeout",
+            "10m",
+        ]);
+        let limits = Limits::from_flags(&flags);
+        assert!(limits.subtask_deadline().is_some());
+        let resources = limits.resources().clone();
+        assert_eq!(resources.max_memory, Some(2 << 20));
+        assert_eq!(resources.max_table_elements, Some(10));
+        assert_eq!(resources.max_instances, Some(1));
+    }
+
+    #[test]
+    fn test_check_deadline() {
+        assert!(Limits::new(None, None).check_deadline().is_ok());
+        let limits = Limits::new(Some(Duration::from_millis(0)), None);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(limits.check_deadline().is_err());
+    }
+}
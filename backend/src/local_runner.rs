@@ -1,26 +1,105 @@
 #![allow(unused)]
 
-use crate::rt::{Engine, Mode, Sandbox};
-use crate::workdir::WorkDir;
-use anyhow::{anyhow, bail, Result as Fallible};
-use gwasm_dispatcher::TaskDef;
-use std::fs::OpenOptions;
+use crate::limits::Limits;
+use crate::progress;
+use crate::rt::{Engine, Mode, RunStats, Sandbox};
+use crate::split_stream::TaskStream;
+use crate::validate::{load_split, SplitCheck};
+use crate::workdir::{place_blob, task_log_paths, WorkDir};
+use anyhow::{anyhow, bail, Context, Result as Fallible};
+use gwasm_dispatcher::{TaskDef, TaskFile};
+use std::fs::{File, OpenOptions};
 use std::io::BufWriter;
 use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often a running split is polled for newly streamed work items.
+const STREAM_POLL: Duration = Duration::from_millis(50);
 
 pub fn run_local_code<E: Engine>(
     engine: E,
+    limits: &Limits,
     wasm_path: &Path,
     task_path: &Path,
     args: Vec<String>,
-) -> Fallible<()> {
-    let mut sandbox = engine.sandbox(args)?;
+) -> Fallible<RunStats> {
+    let _watch = progress::watch("split", task_path);
+    run_host_code(engine, limits, wasm_path, task_path, args, Mode::Rw)
+}
+
+/// Runs the merge step with `merge_path` under `/task_dir/merge`.
+///
+/// With an `output_dir` the host filesystem is mounted read-only and result
+/// files declared by the app are copied there afterwards.
+pub fn run_merge_code<E: Engine>(
+    engine: E,
+    limits: &Limits,
+    wasm_path: &Path,
+    merge_path: &Path,
+    args: Vec<String>,
+    output_dir: Option<&Path>,
+) -> Fallible<RunStats> {
+    let results_path = merge_path.join("results");
+    // Left over by an earlier attempt of a resumed run.
+    for path in &[&results_path, &merge_path.join("next")] {
+        if path.exists() {
+            std::fs::remove_dir_all(path)?;
+        }
+    }
+
+    let host_mode = match output_dir {
+        Some(_) => Mode::Ro,
+        None => Mode::Rw,
+    };
+    let task_path = merge_path
+        .parent()
+        .ok_or_else(|| anyhow!("invalid merge path: {}", merge_path.display()))?;
+    let stats = {
+        let _watch = progress::watch("merge", merge_path);
+        run_host_code(engine, limits, wasm_path, task_path, args, host_mode)?
+    };
+
+    if results_path.exists() {
+        match output_dir {
+            Some(output_dir) => collect_results(&results_path, output_dir)?,
+            None => log::info!(
+                "results left in {}, use --output-dir to collect them",
+                results_path.display()
+            ),
+        }
+    }
+    Ok(stats)
+}
+
+fn collect_results(results_path: &Path, output_dir: &Path) -> Fallible<()> {
+    std::fs::create_dir_all(output_dir)?;
+    for entry in std::fs::read_dir(results_path)? {
+        let entry = entry?;
+        let target_path = output_dir.join(entry.file_name());
+        std::fs::copy(entry.path(), &target_path)?;
+        log::info!("result saved to {}", target_path.display());
+    }
+    Ok(())
+}
+
+fn run_host_code<E: Engine>(
+    engine: E,
+    limits: &Limits,
+    wasm_path: &Path,
+    task_path: &Path,
+    args: Vec<String>,
+    host_mode: Mode,
+) -> Fallible<RunStats> {
+    let mut sandbox = engine.sandbox(args, limits.resources())?;
 
     let mut base = PathBuf::from("/");
     let mut cur_dir = std::env::current_dir()?;
 
     if engine.supports_overlay_mount() {
-        sandbox.mount("/", "@", Mode::Rw)?;
+        sandbox.mount("/", "@", host_mode)?;
     } else {
         let mut it = cur_dir.components();
         let mut c = it.next();
@@ -30,9 +109,9 @@ pub fn run_local_code<E: Engine>(
         }
 
         cur_dir = PathBuf::from("/hostfs").join(it.as_path());
-        sandbox.mount(&base, "/hostfs", Mode::Rw)?;
+        sandbox.mount(&base, "/hostfs", host_mode)?;
         if !engine.supports_workdir() {
-            sandbox.mount(".", ".", Mode::Rw)?;
+            sandbox.mount(".", ".", host_mode)?;
         }
     }
 
@@ -42,124 +121,631 @@ pub fn run_local_code<E: Engine>(
         sandbox.work_dir(cur_dir.to_string_lossy().replace('\\', "/").as_ref())?
     }
 
+    if let Some(deadline) = limits.deadline() {
+        sandbox.deadline(deadline)?;
+    }
     let code = sandbox.for_wasm_path(wasm_path)?;
 
-    sandbox.run(code)?;
+    sandbox.run(code)?.into_result()
+}
 
-    Ok(())
+/// Asks the app to describe itself with the dispatcher `info` command.
+///
+/// Returns `None` for apps built against a dispatcher without `info`, and
+/// fails on runtimes that cannot capture the answer.
+pub fn query_app_info<E: Engine>(
+    engine: E,
+    limits: &Limits,
+    wasm_path: &Path,
+) -> Fallible<Option<serde_json::Value>> {
+    if !engine.supports_output_capture() {
+        bail!(
+            "cannot check the dispatcher protocol of {}, the runtime does not capture guest output",
+            wasm_path.display()
+        );
+    }
+    let mut sandbox = engine.sandbox(vec!["info".to_string()], limits.resources())?;
+    let tmp_path = std::env::temp_dir().join(format!("gwasm-info-{}", std::process::id()));
+    let stdout_path = tmp_path.with_extension("out");
+    let stderr_path = tmp_path.with_extension("err");
+    sandbox.capture_output(File::create(&stdout_path)?, File::create(&stderr_path)?)?;
+    if let Some(deadline) = limits.subtask_deadline() {
+        sandbox.deadline(deadline)?;
+    }
+
+    let code = sandbox.for_wasm_path(wasm_path)?;
+    let outcome = sandbox.run(code);
+    let output = std::fs::read(&stdout_path);
+    let _ = std::fs::remove_file(&stdout_path);
+    let _ = std::fs::remove_file(&stderr_path);
+    if !outcome?.exit.is_success() {
+        return Ok(None);
+    }
+    let info = serde_json::from_slice(&output?)
+        .with_context(|| format!("invalid info output of {}", wasm_path.display()))?;
+    Ok(Some(info))
 }
 
 fn run_remote_code<E: Engine>(
     engine: E,
+    limits: &Limits,
     wasm_path: &Path,
+    idx: usize,
     task_input_path: &Path,
     task_output_path: &Path,
-) -> Fallible<()> {
+) -> Fallible<RunStats> {
     log::info!(
         "starting work in {} => {}",
         task_input_path.display(),
         task_output_path.display()
     );
-    let mut sandbox = engine.sandbox(vec![
-        "exec".to_string(),
-        "/in/task.json".to_string(),
-        "/out/task.json".to_string(),
-    ])?;
+    let mut sandbox = engine.sandbox(
+        vec![
+            "exec".to_string(),
+            "/in/task.json".to_string(),
+            "/out/task.json".to_string(),
+            idx.to_string(),
+        ],
+        limits.resources(),
+    )?;
 
     sandbox.mount(task_input_path, "/in", Mode::Ro)?;
     sandbox.mount(task_output_path, "/out", Mode::Rw)?;
     if engine.supports_workdir() {
         sandbox.work_dir("/in")?;
     }
+    if let Some(deadline) = limits.subtask_deadline() {
+        sandbox.deadline(deadline)?;
+    }
+    let (stdout_path, stderr_path) = task_log_paths(task_input_path.parent().unwrap());
+    if engine.supports_output_capture() {
+        sandbox.capture_output(File::create(stdout_path)?, File::create(&stderr_path)?)?;
+    } else {
+        File::create(stdout_path)?;
+        std::fs::write(
+            &stderr_path,
+            "output capture is not supported by the runtime, guest output went to the terminal\n",
+        )?;
+    }
 
     let code = sandbox.for_wasm_path(wasm_path)?;
-    sandbox.run(code)?;
+    let outcome = {
+        let _watch = progress::watch(format!("subtask {}", idx), task_output_path);
+        sandbox.run(code)?
+    };
+    if !outcome.exit.is_success() {
+        log::error!(
+            "work in {} {}, guest output in {}",
+            task_input_path.display(),
+            outcome.exit,
+            stderr_path.display()
+        );
+    }
+    let stats = outcome.into_result()?;
 
     log::info!(
-        "done work in {} => {}",
+        "done work in {} => {} [{:?}]",
         task_input_path.display(),
-        task_output_path.display()
+        task_output_path.display(),
+        stats.wall_time
     );
+    Ok(stats)
+}
+
+fn stats_json(stats: &RunStats) -> serde_json::Value {
+    serde_json::json!({
+        "fuel": stats.fuel,
+        "wall_time": stats.wall_time.as_secs_f64(),
+        "peak_memory": stats.peak_memory,
+    })
+}
+
+/// Writes per-task resource usage of this attempt to `report.json` in the work dir.
+///
+/// Subtasks reused from a previous attempt are not listed.
+fn write_report(
+    w: &WorkDir,
+    split: Option<&RunStats>,
+    tasks: &[(usize, RunStats)],
+    merge: &RunStats,
+) -> Fallible<()> {
+    let fuel = tasks
+        .iter()
+        .map(|(_, stats)| stats.fuel)
+        .sum::<Option<u64>>();
+    let wall_time = tasks
+        .iter()
+        .map(|(_, stats)| stats.wall_time)
+        .sum::<std::time::Duration>();
+    let report = serde_json::json!({
+        "run_id": w.run_id(),
+        "split": split.map(stats_json),
+        "tasks": tasks.iter().map(|(idx, stats)| {
+            let mut entry = stats_json(stats);
+            entry["index"] = (*idx).into();
+            entry
+        }).collect::<Vec<_>>(),
+        "merge": stats_json(merge),
+        "total": {
+            "fuel": fuel,
+            "wall_time": wall_time.as_secs_f64(),
+        },
+    });
+
+    let report_path = w.report_path();
+    serde_json::to_writer_pretty(
+        BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&report_path)?,
+        ),
+        &report,
+    )?;
+    match fuel {
+        Some(fuel) => log::info!(
+            "subtasks used {} fuel in {:?}, report: {}",
+            fuel,
+            wall_time,
+            report_path.display()
+        ),
+        None => log::info!(
+            "subtasks took {:?}, report: {}",
+            wall_time,
+            report_path.display()
+        ),
+    }
     Ok(())
 }
 
-pub fn run_on_local(engine: impl Engine, wasm_path: &Path, args: &[String]) -> Fallible<()> {
-    let mut w = WorkDir::new("local")?;
+/// Runs `job` for every item on a pool of `workers` threads.
+///
+/// Workers stop picking up new items after the first failure; the error of
+/// the lowest failing item is returned.
+fn run_on_workers<T: Sync>(
+    items: &[T],
+    workers: usize,
+    job: impl Fn(&T) -> Fallible<()> + Sync,
+) -> Fallible<()> {
+    if workers <= 1 || items.len() <= 1 {
+        return items.iter().try_for_each(job);
+    }
 
-    let output_path = w.split_output()?;
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let errors = Mutex::new(Vec::new());
+
+    thread::scope(|s| {
+        for _ in 0..workers.min(items.len()) {
+            s.spawn(|| {
+                while !failed.load(Ordering::SeqCst) {
+                    let idx = next.fetch_add(1, Ordering::SeqCst);
+                    let item = match items.get(idx) {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    if let Err(e) = job(item) {
+                        failed.store(true, Ordering::SeqCst);
+                        errors.lock().unwrap().push((idx, e));
+                    }
+                }
+            });
+        }
+    });
+
+    match errors
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .min_by_key(|(idx, _)| *idx)
     {
-        let mut split_args = Vec::new();
-        split_args.push("split".to_owned());
-        split_args.push("/task_dir/".to_owned());
-        split_args.extend(args.iter().cloned());
-        run_local_code(engine.clone(), wasm_path, &output_path, split_args)?;
+        Some((_, e)) => Err(e),
+        None => Ok(()),
     }
+}
 
-    let tasks_path = output_path.join("tasks.json");
+/// Moves the blobs of a split work item into the dir of subtask `idx`.
+///
+/// Returns the task with outputs rebased into the `out` dir, and the `in` and
+/// `out` dirs of the subtask.
+fn place_task(
+    w: &mut WorkDir,
+    split_path: &Path,
+    idx: usize,
+    task: &TaskDef,
+) -> Fallible<(TaskDef, PathBuf, PathBuf)> {
+    let task_path = w.task_path(idx)?;
+    let task_input_path = task_path.join("in");
+    let task_output_path = task_path.join("out");
 
-    let tasks: Vec<gwasm_dispatcher::TaskDef> =
-        serde_json::from_reader(OpenOptions::new().read(true).open(tasks_path)?)?;
+    std::fs::create_dir_all(&task_input_path)?;
+    std::fs::create_dir_all(&task_output_path)?;
 
-    let mut input_agg = Vec::new();
-    let mut output_agg = Vec::new();
-    let merge_path = w.merge_path()?;
-    for task in tasks {
-        let task_path = w.new_task()?;
-        let task_input_path = task_path.join("in");
-        let task_output_path = task_path.join("out");
-
-        std::fs::create_dir(&task_input_path)?;
-        std::fs::create_dir(&task_output_path)?;
-
-        for blob_path in task.blobs() {
-            std::fs::rename(
-                &output_path.join(blob_path),
-                task_input_path.join(blob_path),
-            )?;
+    for blob in task.blob_refs().chain(task.blob_dirs()) {
+        let source_path = split_path.join(&blob.path);
+        let target_path = task_input_path.join(&blob.path);
+        if source_path.exists() {
+            place_blob(&source_path, &target_path, blob.shared)?;
+        } else if target_path.exists() {
+            // Moved by an earlier attempt of a resumed run.
+            blob.verify(&task_input_path)?;
+        } else {
+            bail!("missing blob {}", source_path.display());
         }
-        let task = task.rebase_output("", "../out/");
-        serde_json::to_writer_pretty(
-            BufWriter::new(
-                OpenOptions::new()
-                    .create_new(true)
-                    .write(true)
-                    .open(task_input_path.join("task.json"))?,
-            ),
-            &task,
-        )?;
+    }
+    let task = task.clone().rebase_output("", "../out/");
+    Ok((task, task_input_path, task_output_path))
+}
 
-        run_remote_code(
+fn write_task(w: &WorkDir, task_input_path: &Path, task: &TaskDef) -> Fallible<()> {
+    serde_json::to_writer_pretty(
+        BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(task_input_path.join("task.json"))?,
+        ),
+        &w.task_file(task),
+    )?;
+    Ok(())
+}
+
+/// Outcome of a split run by `run_streaming_split`.
+struct StreamedSplit {
+    stats: RunStats,
+    tasks: Vec<TaskDef>,
+    /// Subtasks already run, always the first ones.
+    task_stats: Vec<(usize, RunStats)>,
+}
+
+/// Runs split, passing every work item to `on_task` as soon as the app streams
+/// it, while the split is still producing the rest.
+///
+/// Returns the split stats and the streamed items, or `None` for apps that do
+/// not stream. Polling stops early once `cancelled` is set.
+fn stream_split<E: Engine + Send + Sync>(
+    engine: E,
+    limits: &Limits,
+    wasm_path: &Path,
+    args: &[String],
+    split_path: &Path,
+    cancelled: &AtomicBool,
+    on_task: &mut dyn FnMut(usize, &TaskDef) -> Fallible<()>,
+) -> Fallible<(RunStats, Option<Vec<TaskDef>>)> {
+    let mut split_args = Vec::new();
+    split_args.push("split".to_owned());
+    split_args.push("/task_dir/".to_owned());
+    split_args.extend(args.iter().cloned());
+    // Left over by an earlier attempt of a resumed run.
+    let stream_path = split_path.join("tasks.ndjson");
+    if stream_path.exists() {
+        std::fs::remove_file(&stream_path)?;
+    }
+
+    let (split_result, streamed) = thread::scope(|s| {
+        let split =
+            s.spawn(|| run_local_code(engine.clone(), limits, wasm_path, split_path, split_args));
+        let streamed = (|| -> Fallible<Option<Vec<TaskDef>>> {
+            let mut stream = TaskStream::new(split_path);
+            let mut check = SplitCheck::default();
+            let mut tasks = Vec::new();
+            loop {
+                // Checked first, so the last read sees everything the split wrote.
+                let done = split.is_finished();
+                for task in stream.read_available()? {
+                    let idx = tasks.len();
+                    let problems = check.check(split_path, idx, &task);
+                    for problem in &problems {
+                        log::error!("{}", problem);
+                    }
+                    if !problems.is_empty() {
+                        bail!("invalid work item {} in {}", idx, stream_path.display());
+                    }
+                    on_task(idx, &task)?;
+                    tasks.push(task);
+                }
+                if done || cancelled.load(Ordering::SeqCst) {
+                    break;
+                }
+                thread::sleep(STREAM_POLL);
+            }
+            Ok(if stream.is_open() { Some(tasks) } else { None })
+        })();
+        (split.join().unwrap(), streamed)
+    });
+    Ok((split_result?, streamed?))
+}
+
+/// Runs split for backends that start subtasks elsewhere, passing every work
+/// item to `on_task` as soon as it is known.
+///
+/// Items of apps that do not stream are passed once the split is done.
+pub fn run_split_streaming<E: Engine + Send + Sync>(
+    engine: E,
+    limits: &Limits,
+    wasm_path: &Path,
+    args: &[String],
+    split_path: &Path,
+    cancelled: &AtomicBool,
+    on_task: &mut dyn FnMut(usize, &TaskDef) -> Fallible<()>,
+) -> Fallible<Vec<TaskDef>> {
+    match stream_split(
+        engine, limits, wasm_path, args, split_path, cancelled, on_task,
+    )? {
+        (_, Some(tasks)) => Ok(tasks),
+        (_, None) => {
+            let tasks = load_split(split_path)?;
+            for (idx, task) in tasks.iter().enumerate() {
+                on_task(idx, task)?;
+            }
+            Ok(tasks)
+        }
+    }
+}
+
+/// Runs split, starting subtasks for work items as soon as the split streams
+/// them, while it is still producing the rest.
+///
+/// Apps that do not stream get no subtasks run here.
+fn run_streaming_split<E: Engine + Send + Sync>(
+    engine: E,
+    limits: &Limits,
+    w: &mut WorkDir,
+    wasm_path: &Path,
+    workers: usize,
+    args: &[String],
+    split_path: &Path,
+) -> Fallible<StreamedSplit> {
+    let (sender, receiver) = mpsc::channel::<(usize, PathBuf, PathBuf)>();
+    let receiver = Mutex::new(receiver);
+    let failed = AtomicBool::new(false);
+    let errors = Mutex::new(Vec::new());
+    let task_stats = Mutex::new(Vec::new());
+
+    let split = thread::scope(|s| {
+        for _ in 0..workers.max(1) {
+            s.spawn(|| loop {
+                let job = receiver.lock().unwrap().recv();
+                let (idx, task_input_path, task_output_path) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                if failed.load(Ordering::SeqCst) {
+                    continue;
+                }
+                match run_remote_code(
+                    engine.clone(),
+                    limits,
+                    wasm_path,
+                    idx,
+                    &task_input_path,
+                    &task_output_path,
+                ) {
+                    Ok(stats) => task_stats.lock().unwrap().push((idx, stats)),
+                    Err(e) => {
+                        failed.store(true, Ordering::SeqCst);
+                        errors.lock().unwrap().push((idx, e));
+                    }
+                }
+            });
+        }
+
+        let split = stream_split(
             engine.clone(),
+            limits,
             wasm_path,
-            &task_input_path,
-            &task_output_path,
-        )?;
+            args,
+            split_path,
+            &failed,
+            &mut |idx, task| {
+                let (task_def, task_input_path, task_output_path) =
+                    place_task(w, split_path, idx, task)?;
+                write_task(w, &task_input_path, &task_def)?;
+                let _ = sender.send((idx, task_input_path, task_output_path));
+                Ok(())
+            },
+        );
+        if split.is_err() {
+            failed.store(true, Ordering::SeqCst);
+        }
+        drop(sender);
+        split
+    });
 
-        let output_data: TaskDef = serde_json::from_reader(
-            OpenOptions::new()
-                .read(true)
-                .open(task_output_path.join("task.json"))?,
+    let (stats, tasks) = split?;
+    if let Some((_, e)) = errors
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .min_by_key(|(idx, _)| *idx)
+    {
+        return Err(e);
+    }
+    Ok(match tasks {
+        Some(tasks) => {
+            log::info!("ran {} subtasks while splitting", tasks.len());
+            StreamedSplit {
+                stats,
+                tasks,
+                task_stats: task_stats.into_inner().unwrap(),
+            }
+        }
+        None => StreamedSplit {
+            stats,
+            tasks: load_split(split_path)?,
+            task_stats: Vec::new(),
+        },
+    })
+}
+
+pub fn run_on_local<E: Engine + Send + Sync>(
+    engine: E,
+    limits: &Limits,
+    mut w: WorkDir,
+    wasm_path: &Path,
+    workers: usize,
+    args: &[String],
+    output_dir: Option<&Path>,
+) -> Fallible<()> {
+    loop {
+        run_round(
+            engine.clone(),
+            limits,
+            &mut w,
+            wasm_path,
+            workers,
+            args,
+            output_dir,
         )?;
+        if !w.next_round()? {
+            return Ok(());
+        }
+    }
+}
+
+fn run_round<E: Engine + Send + Sync>(
+    engine: E,
+    limits: &Limits,
+    w: &mut WorkDir,
+    wasm_path: &Path,
+    workers: usize,
+    args: &[String],
+    output_dir: Option<&Path>,
+) -> Fallible<()> {
+    let output_path = w.split_output()?;
+    let mut split_stats = None;
+    let mut task_stats = Vec::new();
+    let tasks = match w.finished_split() {
+        Some(tasks) => {
+            if w.round() == 0 {
+                log::info!("reusing split from previous attempt");
+            }
+            tasks
+        }
+        None => {
+            let split = run_streaming_split(
+                engine.clone(),
+                limits,
+                w,
+                wasm_path,
+                workers,
+                args,
+                &output_path,
+            )?;
+            split_stats = Some(split.stats);
+            task_stats = split.task_stats;
+            split.tasks
+        }
+    };
+    // Subtasks run while splitting, always the first ones.
+    let streamed = task_stats.len();
+
+    let merge_path = w.merge_path()?;
+    let mut tasks = tasks;
+    let mut subtasks = Vec::new();
+    let mut outputs = Vec::new();
+    let mut spawned = Vec::new();
+    // Work items spawned by a wave of subtasks run in the next wave.
+    let mut wave_start = 0;
+    while wave_start < tasks.len() {
+        let wave_end = tasks.len();
+        let mut pending = Vec::new();
+        let mut reused = 0;
+        for (idx, task) in (wave_start..).zip(&tasks[wave_start..wave_end]) {
+            let (task, task_input_path, task_output_path) = place_task(w, &output_path, idx, task)?;
+            if idx < streamed {
+                // Already run while splitting.
+            } else if w.finished_task(idx).is_none() {
+                write_task(w, &task_input_path, &task)?;
+                pending.push(idx);
+            } else {
+                reused += 1;
+            }
+            subtasks.push((task, task_input_path, task_output_path));
+        }
+
+        if reused > 0 {
+            log::info!("skipping {} subtasks finished by previous attempt", reused);
+        }
+        if !pending.is_empty() {
+            log::info!(
+                "running {} subtasks on {} worker(s)",
+                pending.len(),
+                workers
+            );
+        }
+        let wave_stats = Mutex::new(Vec::with_capacity(pending.len()));
+        run_on_workers(&pending, workers, |&idx| {
+            let (_, task_input_path, task_output_path) = &subtasks[idx];
+            let stats = run_remote_code(
+                engine.clone(),
+                limits,
+                wasm_path,
+                idx,
+                task_input_path,
+                task_output_path,
+            )?;
+            wave_stats.lock().unwrap().push((idx, stats));
+            Ok(())
+        })?;
+        task_stats.extend(wave_stats.into_inner().unwrap());
 
+        // Spawned subtasks are numbered in split order, regardless of completion order.
+        for (_, _, task_output_path) in &subtasks[wave_start..wave_end] {
+            let output_data = serde_json::from_reader::<_, TaskFile<TaskDef>>(
+                OpenOptions::new()
+                    .read(true)
+                    .open(task_output_path.join("task.json"))?,
+            )?
+            .data;
+            if let Some(e) = output_data.failure() {
+                log::warn!("subtask {} failed: {}", task_output_path.display(), e);
+            }
+            let children = w.spawned_tasks(task_output_path, &output_data, tasks.len())?;
+            spawned.push((tasks.len()..tasks.len() + children.len()).collect::<Vec<_>>());
+            tasks.extend(children);
+            outputs.push(output_data);
+        }
+        if tasks.len() > wave_end {
+            log::info!("subtasks spawned {} more", tasks.len() - wave_end);
+        }
+        wave_start = wave_end;
+    }
+    task_stats.sort_by_key(|(idx, _)| *idx);
+
+    // Outputs are aggregated in split order, regardless of completion order.
+    let mut input_agg = Vec::new();
+    let mut output_agg = Vec::new();
+    for (((task, task_input_path, task_output_path), output_data), children) in
+        subtasks.into_iter().zip(outputs).zip(spawned)
+    {
         input_agg.push(task.rebase_to(&task_input_path, &merge_path)?);
-        output_agg.push(output_data.rebase_to(&task_output_path, &merge_path)?);
+        output_agg.push(
+            output_data
+                .link_spawned(children)
+                .rebase_to(&task_output_path, &merge_path)?,
+        );
     }
     serde_json::to_writer_pretty(
         OpenOptions::new()
-            .create_new(true)
+            .create(true)
+            .truncate(true)
             .write(true)
             .open(merge_path.join("tasks_input.json"))?,
-        &input_agg,
+        &w.task_file(&input_agg),
     )?;
     serde_json::to_writer_pretty(
         OpenOptions::new()
-            .create_new(true)
+            .create(true)
+            .truncate(true)
             .write(true)
             .open(merge_path.join("tasks_output.json"))?,
-        &output_agg,
+        &w.task_file(&output_agg),
     )?;
 
-    {
+    let merge_stats = {
         let mut merge_args = vec![
             "merge".to_owned(),
             "/task_dir/merge/tasks_input.json".to_owned(),
@@ -168,8 +754,15 @@ pub fn run_on_local(engine: impl Engine, wasm_path: &Path, args: &[String]) -> F
         ];
 
         merge_args.extend(args.iter().cloned());
-        run_local_code(engine, wasm_path, merge_path.parent().unwrap(), merge_args)?;
-    }
+        run_merge_code(
+            engine,
+            limits,
+            wasm_path,
+            &merge_path,
+            merge_args,
+            output_dir,
+        )?
+    };
 
-    Ok(())
+    write_report(w, split_stats.as_ref(), &task_stats, &merge_stats)
 }
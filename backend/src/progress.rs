@@ -0,0 +1,144 @@
+use gwasm_dispatcher::progress::Progress;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// How often progress files of watched steps are read.
+const PROGRESS_POLL: Duration = Duration::from_millis(200);
+/// Bar length, fractions are shown in steps of 0.1%.
+const BAR_LENGTH: u64 = 1000;
+
+struct Watched {
+    label: String,
+    dir: PathBuf,
+    /// Created on the first report, so steps that never report get no bar.
+    bar: Option<ProgressBar>,
+}
+
+struct Board {
+    bars: MultiProgress,
+    watched: Mutex<BTreeMap<u64, Watched>>,
+    next_id: AtomicU64,
+}
+
+/// Bars of all running steps share a single board, as they share the terminal.
+fn board() -> &'static Board {
+    static BOARD: OnceLock<Board> = OnceLock::new();
+    BOARD.get_or_init(|| {
+        thread::spawn(|| loop {
+            thread::sleep(PROGRESS_POLL);
+            board().poll();
+        });
+        Board {
+            bars: MultiProgress::new(),
+            watched: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+        }
+    })
+}
+
+impl Board {
+    fn poll(&self) {
+        for Watched { label, dir, bar } in self.watched.lock().unwrap().values_mut() {
+            let progress = match Progress::read(dir) {
+                Some(progress) => progress,
+                None => continue,
+            };
+            let bar = bar.get_or_insert_with(|| {
+                let bar = self.bars.add(ProgressBar::new(BAR_LENGTH));
+                bar.set_style(
+                    ProgressStyle::with_template("{prefix:>12} [{bar:30}] {percent:>3}% {msg}")
+                        .unwrap()
+                        .progress_chars("=> "),
+                );
+                bar.set_prefix(label.clone());
+                bar
+            });
+            bar.set_position((progress.fraction * BAR_LENGTH as f64) as u64);
+            bar.set_message(progress.message);
+        }
+    }
+}
+
+/// Shows progress reported by the step running in `dir` until dropped.
+pub(crate) struct Watch {
+    id: u64,
+}
+
+pub(crate) fn watch(label: impl Into<String>, dir: &Path) -> Watch {
+    let board = board();
+    let id = board.next_id.fetch_add(1, Ordering::SeqCst);
+    board.watched.lock().unwrap().insert(
+        id,
+        Watched {
+            label: label.into(),
+            dir: dir.to_owned(),
+            bar: None,
+        },
+    );
+    Watch { id }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let board = board();
+        let watched = board.watched.lock().unwrap().remove(&self.id);
+        if let Some(bar) = watched.and_then(|watched| watched.bar) {
+            bar.finish_and_clear();
+            board.bars.remove(&bar);
+        }
+    }
+}
+
+/// Bar of finished subtasks, for backends that cannot relay the progress of
+/// subtasks while they run remotely.
+pub fn subtasks_bar(count: usize) -> ProgressBar {
+    let bar = board().bars.add(ProgressBar::new(count as u64));
+    bar.set_style(
+        ProgressStyle::with_template("{prefix:>12} [{bar:30}] {pos}/{len}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    bar.set_prefix("subtasks");
+    bar
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn position(watch: &Watch) -> Option<u64> {
+        board().watched.lock().unwrap()[&watch.id]
+            .bar
+            .as_ref()
+            .map(ProgressBar::position)
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = std::env::temp_dir().join("gwr-backend-test_watch");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let watch = watch("subtask 0", &dir);
+        board().poll();
+        assert_eq!(position(&watch), None);
+
+        std::fs::write(
+            dir.join(gwasm_dispatcher::progress::PROGRESS_FILE),
+            r#"{"fraction": 0.25, "message": "tile 1/4"}"#,
+        )
+        .unwrap();
+        board().poll();
+        assert_eq!(position(&watch), Some(250));
+
+        let id = watch.id;
+        drop(watch);
+        assert!(!board().watched.lock().unwrap().contains_key(&id));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{anyhow, Result as Fallible};
+use gwasm_dispatcher::TaskDef;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Work items streamed by a running split to `tasks.ndjson`, one per line.
+pub(crate) struct TaskStream {
+    path: PathBuf,
+    reader: Option<BufReader<File>>,
+    line: Vec<u8>,
+}
+
+impl TaskStream {
+    pub(crate) fn new(split_dir: &Path) -> Self {
+        TaskStream {
+            path: split_dir.join("tasks.ndjson"),
+            reader: None,
+            line: Vec::new(),
+        }
+    }
+
+    /// Whether the split started streaming, which apps built before streaming never do.
+    pub(crate) fn is_open(&self) -> bool {
+        self.reader.is_some()
+    }
+
+    /// Returns work items completed since the last call.
+    ///
+    /// A partially written line is kept until the rest of it arrives.
+    pub(crate) fn read_available(&mut self) -> Fallible<Vec<TaskDef>> {
+        if self.reader.is_none() {
+            match File::open(&self.path) {
+                Ok(file) => self.reader = Some(BufReader::new(file)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let (path, reader, line) = match self {
+            TaskStream {
+                path,
+                reader: Some(reader),
+                line,
+            } => (path, reader, line),
+            _ => unreachable!(),
+        };
+
+        let mut tasks = Vec::new();
+        while reader.read_until(b'\n', line)? > 0 && line.ends_with(b"\n") {
+            tasks.push(
+                serde_json::from_slice(line)
+                    .map_err(|e| anyhow!("invalid work item in {}: {}", path.display(), e))?,
+            );
+            line.clear();
+        }
+        Ok(tasks)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    #[test]
+    fn test_partial_lines() {
+        let split_dir = std::env::temp_dir().join("gwr-backend-test_partial_lines");
+        let _ = std::fs::remove_dir_all(&split_dir);
+        std::fs::create_dir_all(&split_dir).unwrap();
+
+        let mut stream = TaskStream::new(&split_dir);
+        assert!(stream.read_available().unwrap().is_empty());
+        assert!(!stream.is_open());
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(split_dir.join("tasks.ndjson"))
+            .unwrap();
+        file.write_all(b"[{\"meta\": 1}]\n[{\"me").unwrap();
+        assert_eq!(stream.read_available().unwrap().len(), 1);
+        assert!(stream.is_open());
+        file.write_all(b"ta\": 2}]\n").unwrap();
+        let tasks = stream.read_available().unwrap();
+        assert_eq!(
+            tasks,
+            vec![TaskDef(vec![gwasm_dispatcher::TaskArg::Meta(2.into())])]
+        );
+
+        std::fs::remove_dir_all(&split_dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{anyhow, bail, Result as Fallible};
+use gwasm_dispatcher::{TaskArg, TaskDef, TaskFile};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Component, Path};
+
+/// Reads `tasks.json` of a fresh split and checks it before any subtask runs.
+///
+/// Every blob must exist below the split dir and match its digest, unshared
+/// blobs and outputs must belong to a single work item.
+pub fn load_split(split_dir: &Path) -> Fallible<Vec<TaskDef>> {
+    let tasks_path = split_dir.join("tasks.json");
+    let tasks: TaskFile<Vec<TaskDef>> =
+        serde_json::from_reader(BufReader::new(File::open(&tasks_path)?))
+            .map_err(|e| anyhow!("invalid {}: {}", tasks_path.display(), e))?;
+    let tasks = tasks.data;
+
+    let problems = check_tasks(split_dir, &tasks);
+    for problem in &problems {
+        log::error!("{}", problem);
+    }
+    if !problems.is_empty() {
+        bail!("{} problem(s) in {}", problems.len(), tasks_path.display());
+    }
+    Ok(tasks)
+}
+
+fn is_relative(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fn check_tasks(split_dir: &Path, tasks: &[TaskDef]) -> Vec<String> {
+    let mut check = SplitCheck::default();
+    tasks
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, task)| check.check(split_dir, idx, task))
+        .collect()
+}
+
+/// Checks split work items one at a time, against the blobs and outputs of
+/// the items before them.
+#[derive(Default)]
+pub struct SplitCheck {
+    /// Blob paths seen so far, with their `shared` flag.
+    blobs: HashMap<String, bool>,
+    outputs: HashSet<String>,
+}
+
+impl SplitCheck {
+    /// Returns the problems found in work item `idx`.
+    pub fn check(&mut self, split_dir: &Path, idx: usize, task: &TaskDef) -> Vec<String> {
+        let mut problems = Vec::new();
+        for arg in &task.0 {
+            match arg {
+                TaskArg::Meta(_) => (),
+                TaskArg::Blob(blob) | TaskArg::BlobDir(blob) => {
+                    if !is_relative(&blob.path) {
+                        problems.push(format!(
+                            "work item {}: invalid blob path {}",
+                            idx, blob.path
+                        ));
+                        continue;
+                    }
+                    match self.blobs.get(&blob.path) {
+                        Some(true) if blob.shared => continue,
+                        Some(_) => {
+                            problems.push(format!(
+                                "work item {}: blob {} is used by more than one work item but not shared",
+                                idx, blob.path
+                            ));
+                            continue;
+                        }
+                        None => (),
+                    }
+                    self.blobs.insert(blob.path.clone(), blob.shared);
+
+                    let path = split_dir.join(&blob.path);
+                    let is_dir = matches!(arg, TaskArg::BlobDir(_));
+                    if !path.exists() {
+                        problems.push(format!("work item {}: missing blob {}", idx, blob.path));
+                    } else if path.is_dir() != is_dir {
+                        problems.push(format!(
+                            "work item {}: blob {} is not a {}",
+                            idx,
+                            blob.path,
+                            if is_dir { "dir" } else { "file" }
+                        ));
+                    } else if let Err(e) = blob.verify(split_dir) {
+                        problems.push(format!("work item {}: {}", idx, e));
+                    }
+                }
+                TaskArg::Output(blob) | TaskArg::OutputDir(blob) => {
+                    if !is_relative(&blob.path) {
+                        problems.push(format!(
+                            "work item {}: invalid output path {}",
+                            idx, blob.path
+                        ));
+                    } else if !self.outputs.insert(blob.path.clone()) {
+                        problems.push(format!(
+                            "work item {}: output {} is not unique",
+                            idx, blob.path
+                        ));
+                    }
+                }
+                TaskArg::Error(_) | TaskArg::Spawn(_) | TaskArg::Spawned(_) => problems.push(
+                    format!("work item {}: unexpected failure or spawn record", idx),
+                ),
+            }
+        }
+        problems
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_check_tasks() {
+        let split_dir = std::env::temp_dir().join("gwr-backend-test_check_tasks");
+        let _ = fs::remove_dir_all(&split_dir);
+        fs::create_dir_all(split_dir.join("shared")).unwrap();
+        fs::write(split_dir.join("000001.bin"), b"a").unwrap();
+        fs::write(split_dir.join("shared/000002.bin"), b"b").unwrap();
+
+        let tasks: Vec<TaskDef> = serde_json::from_str(
+            r#"[
+                [{"blob": "000001.bin"}, {"blob": {"path": "shared/000002.bin", "shared": true}}, {"output": "000003.bin"}],
+                [{"blob": {"path": "shared/000002.bin", "shared": true}}, {"output": "000004.bin"}]
+            ]"#,
+        )
+        .unwrap();
+        assert!(check_tasks(&split_dir, &tasks).is_empty());
+
+        let tasks: Vec<TaskDef> = serde_json::from_str(
+            r#"[
+                [{"blob": "000001.bin"}, {"blob": "missing.bin"}, {"output": "000003.bin"}],
+                [{"blob": "000001.bin"}, {"blobdir": "shared/000002.bin"}, {"output": "000003.bin"}],
+                [{"blob": "../000001.bin"}]
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(check_tasks(&split_dir, &tasks).len(), 5);
+
+        fs::remove_dir_all(&split_dir).unwrap();
+    }
+}
@@ -3,26 +3,214 @@
 use super::GWASM_APP_INFO;
 use anyhow::{anyhow, bail, Result as Fallible};
 use app_dirs::{app_dir, AppDataType::UserCache, AppInfo};
-use std::fs;
-use std::path::PathBuf;
+use gwasm_dispatcher::dispatcher::PROTOCOL_VERSION;
+use gwasm_dispatcher::{TaskArg, TaskDef, TaskFile};
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
 
+/// Files capturing guest stdout and stderr of a subtask, kept next to its
+/// `in` and `out` dirs.
+///
+/// Only the Local backend with the wasmtime runtime captures both streams.
+/// spwasm guests print to the terminal, and their `stderr.log` only says so.
+/// Yagna and GU keep what providers
+/// report: the message of the run command, or the output of the exec command,
+/// in `stdout.log`, and messages of the other commands in `stderr.log`.
+pub fn task_log_paths(task_path: &Path) -> (PathBuf, PathBuf) {
+    (task_path.join("stdout.log"), task_path.join("stderr.log"))
+}
+
+/// Places a blob file or dir into a subtask input dir.
+///
+/// Shared blobs stay in the split dir and are hard linked file by file, or
+/// copied where links are not supported; other blobs are moved. Anything at
+/// `to`, like a blob left by an earlier attempt, is replaced.
+pub fn place_blob(from: &Path, to: &Path, shared: bool) -> Fallible<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if to.is_dir() {
+        fs::remove_dir_all(to)?;
+    } else if to.exists() {
+        fs::remove_file(to)?;
+    }
+    if !shared {
+        fs::rename(from, to)?;
+    } else if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            place_blob(&entry.path(), &to.join(entry.file_name()), true)?;
+        }
+    } else if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Dir of the given round of the run in `root`.
+fn round_path(root: &Path, round: usize) -> PathBuf {
+    match round {
+        0 => root.to_owned(),
+        round => root.join(format!("round-{:03}", round)),
+    }
+}
+
+/// Directory tree of a single run.
+///
+/// Round 0 lives directly in the run dir; every further round of an iterative
+/// app gets its own `round-NNN` dir with the same layout.
 #[derive(Debug, Clone)]
 pub struct WorkDir {
+    root: PathBuf,
     base: PathBuf,
+    run_id: String,
+    resumed: bool,
+    round: usize,
+    app_protocol: u32,
 }
 
 impl WorkDir {
     pub fn new(task_type: &'static str) -> Fallible<Self> {
-        let uuid = uuid::Uuid::new_v4();
-        let base = app_dir(UserCache, &GWASM_APP_INFO, task_type)?
-            .join(uuid.to_hyphenated_ref().to_string());
-        Ok(WorkDir { base })
+        Self::new_in(&app_dir(UserCache, &GWASM_APP_INFO, task_type)?)
+    }
+
+    /// Creates a work dir for a new run below `runs_dir`.
+    fn new_in(runs_dir: &Path) -> Fallible<Self> {
+        let run_id = uuid::Uuid::new_v4().to_hyphenated_ref().to_string();
+        let base = runs_dir.join(&run_id);
+        Ok(WorkDir {
+            root: base.clone(),
+            base,
+            run_id,
+            resumed: false,
+            round: 0,
+            app_protocol: PROTOCOL_VERSION,
+        })
+    }
+
+    /// Opens the work dir of a previous run.
+    ///
+    /// The run continues with the last round it started, rounds before it
+    /// are not merged again.
+    pub fn resume(task_type: &'static str, run_id: &str) -> Fallible<Self> {
+        if run_id.is_empty() || run_id.contains(&['/', '\\', '.'][..]) {
+            bail!("invalid run id: {:?}", run_id);
+        }
+        Self::resume_in(&app_dir(UserCache, &GWASM_APP_INFO, task_type)?, run_id)
+    }
+
+    /// Opens the work dir of the run `run_id` below `runs_dir`.
+    fn resume_in(runs_dir: &Path, run_id: &str) -> Fallible<Self> {
+        let root = runs_dir.join(run_id);
+        if !root.is_dir() {
+            bail!(
+                "no previous run with id {} in {}",
+                run_id,
+                runs_dir.display()
+            );
+        }
+        let mut round = 0;
+        // Rounds start with their split results moved in place by `next_round`.
+        while round_path(&root, round + 1)
+            .join("split")
+            .join("tasks.json")
+            .is_file()
+        {
+            round += 1;
+        }
+        if round > 0 {
+            log::info!("resuming at round {}", round);
+        }
+        Ok(WorkDir {
+            base: round_path(&root, round),
+            root,
+            run_id: run_id.to_owned(),
+            resumed: true,
+            round,
+            app_protocol: PROTOCOL_VERSION,
+        })
+    }
+
+    /// Opens the work dir selected by `--resume`, or creates a fresh one.
+    pub fn for_run(task_type: &'static str, resume: Option<&str>) -> Fallible<Self> {
+        let w = match resume {
+            Some(run_id) => WorkDir::resume(task_type, run_id)?,
+            None => WorkDir::new(task_type)?,
+        };
+        log::info!("run id: {} [{}]", w.run_id, w.base.display());
+        Ok(w)
     }
 
     pub fn base_dir(&self) -> &PathBuf {
         &self.base
     }
 
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Sets the dispatcher protocol of the app, as returned by `check_app`.
+    pub fn set_app_protocol(&mut self, protocol: u32) {
+        self.app_protocol = protocol;
+    }
+
+    pub fn app_protocol(&self) -> u32 {
+        self.app_protocol
+    }
+
+    /// Wraps task file contents in the format read by the app of this run.
+    pub fn task_file<T>(&self, data: T) -> TaskFile<T> {
+        TaskFile {
+            version: self.app_protocol,
+            data,
+        }
+    }
+
+    /// Index of the current round, 0 for the initial split.
+    pub fn round(&self) -> usize {
+        self.round
+    }
+
+    /// Moves on to the next round if the last merge returned new work items.
+    ///
+    /// Those items become the split results of the new round. Returns `false`
+    /// once the app is done.
+    pub fn next_round(&mut self) -> Fallible<bool> {
+        let next_path = self.base.join("merge").join("next");
+        if !next_path.join("tasks.json").is_file() {
+            return Ok(false);
+        }
+
+        self.round += 1;
+        self.base = round_path(&self.root, self.round);
+        let split_path = self.base.join("split");
+        // A resumed run merges again, so its items replace those of the previous attempt.
+        if split_path.exists() {
+            fs::remove_dir_all(&split_path)?;
+        }
+        fs::create_dir_all(&self.base)?;
+        fs::rename(&next_path, &split_path)?;
+        log::info!("starting round {}", self.round);
+        Ok(true)
+    }
+
+    /// Returns split results left by a previous attempt of this run, or by
+    /// the merge of the previous round.
+    pub fn finished_split(&self) -> Option<Vec<TaskDef>> {
+        if !self.resumed && self.round == 0 {
+            return None;
+        }
+        let tasks_path = self.base.join("split").join("tasks.json");
+        let tasks: TaskFile<Vec<TaskDef>> =
+            serde_json::from_reader(OpenOptions::new().read(true).open(tasks_path).ok()?).ok()?;
+        Some(tasks.data)
+    }
+
     pub fn split_output(&mut self) -> Fallible<PathBuf> {
         let output = self.base.join("split");
 
@@ -37,18 +225,200 @@ impl WorkDir {
         Ok(output)
     }
 
-    pub fn new_task(&mut self) -> Fallible<PathBuf> {
-        let uuid = format!("tsk-{}", uuid::Uuid::new_v4().to_hyphenated());
-        let task_path = self.base.join(uuid);
-        fs::create_dir(&task_path)?;
+    /// Returns work items spawned by a finished subtask as split tasks for the
+    /// subtasks `first_idx..`.
+    ///
+    /// Their blobs are copied from the subtask output next to the split
+    /// results, so they are found like the blobs of split work items.
+    pub fn spawned_tasks(
+        &mut self,
+        task_output_path: &Path,
+        output: &TaskDef,
+        first_idx: usize,
+    ) -> Fallible<Vec<TaskDef>> {
+        let split_path = self.split_output()?;
+        let mut tasks = Vec::new();
+        for (idx, task) in (first_idx..).zip(output.spawned()) {
+            let mut task = task.clone();
+            for arg in &mut task.0 {
+                if let TaskArg::Blob(blob) | TaskArg::BlobDir(blob) = arg {
+                    // Every spawned item gets its own copy.
+                    blob.shared = false;
+                    let name = format!("spawn-{:06}-{}", idx, blob.path.replace('/', "_"));
+                    place_blob(
+                        &task_output_path.join(&blob.path),
+                        &split_path.join(&name),
+                        true,
+                    )?;
+                    blob.path = name;
+                }
+            }
+            tasks.push(task);
+        }
+        Ok(tasks)
+    }
+
+    /// Location of the resource usage report of the current round.
+    pub fn report_path(&self) -> PathBuf {
+        self.base.join("report.json")
+    }
+
+    /// Directory of the subtask with the given split index.
+    ///
+    /// Names are stable, so a resumed run finds the same directory again.
+    pub fn task_path(&mut self, idx: usize) -> Fallible<PathBuf> {
+        let task_path = self.base.join(format!("tsk-{:06}", idx));
+        fs::create_dir_all(&task_path)?;
         Ok(task_path)
     }
+
+    /// Returns the output of a subtask finished by a previous attempt of this run.
+    ///
+    /// A subtask counts as finished when its `out/task.json` parses and every
+    /// blob it lists is present next to it, matching its recorded digest.
+    pub fn finished_task(&self, idx: usize) -> Option<TaskDef> {
+        if !self.resumed {
+            return None;
+        }
+        let output_path = self.base.join(format!("tsk-{:06}", idx)).join("out");
+        let task_def = serde_json::from_reader::<_, TaskFile<TaskDef>>(
+            OpenOptions::new()
+                .read(true)
+                .open(output_path.join("task.json"))
+                .ok()?,
+        )
+        .ok()?
+        .data;
+        if task_def
+            .blobs()
+            .into_iter()
+            .all(|blob_path| output_path.join(blob_path).is_file())
+            && task_def.verify_blobs(&output_path).is_ok()
+        {
+            Some(task_def)
+        } else {
+            None
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    /// Fresh dir holding the runs of a test, in place of the user cache dir.
+    fn runs_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gwr-backend-test_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resume_finished_task() {
+        let runs_dir = runs_dir("resume_finished_task");
+        let mut w = WorkDir::new_in(&runs_dir).unwrap();
+        let output_path = w.task_path(3).unwrap().join("out");
+        fs::create_dir_all(&output_path).unwrap();
+        fs::write(output_path.join("task.json"), r#"[{"blob": "000001.bin"}]"#).unwrap();
+
+        let run_id = w.run_id().to_owned();
+        assert!(w.finished_task(3).is_none());
+
+        let w = WorkDir::resume_in(&runs_dir, &run_id).unwrap();
+        assert!(w.finished_task(3).is_none());
+        fs::write(output_path.join("000001.bin"), b"data").unwrap();
+        assert!(w.finished_task(3).is_some());
+        assert!(w.finished_task(4).is_none());
+
+        fs::remove_dir_all(&runs_dir).unwrap();
+        assert!(WorkDir::resume_in(&runs_dir, &run_id).is_err());
+    }
+
+    #[test]
+    fn test_spawned_tasks() {
+        let runs_dir = runs_dir("spawned_tasks");
+        let mut w = WorkDir::new_in(&runs_dir).unwrap();
+        let output_path = w.task_path(0).unwrap().join("out");
+        fs::create_dir_all(&output_path).unwrap();
+        fs::write(output_path.join("part.bin"), b"data").unwrap();
+        let output: TaskDef =
+            serde_json::from_str(r#"[{"meta": 1}, {"spawn": [[{"blob": "part.bin"}]]}]"#).unwrap();
+
+        let tasks = w.spawned_tasks(&output_path, &output, 5).unwrap();
+        assert_eq!(
+            serde_json::to_string(&tasks).unwrap(),
+            r#"[[{"blob":"spawn-000005-part.bin"}]]"#
+        );
+        assert!(w.base_dir().join("split/spawn-000005-part.bin").is_file());
+
+        fs::remove_dir_all(&runs_dir).unwrap();
+    }
+
+    #[test]
+    fn test_next_round() {
+        let runs_dir = runs_dir("next_round");
+        let mut w = WorkDir::new_in(&runs_dir).unwrap();
+        let next_path = w.merge_path().unwrap().join("next");
+        assert!(!w.next_round().unwrap());
+
+        fs::create_dir_all(&next_path).unwrap();
+        fs::write(next_path.join("tasks.json"), r#"[[{"meta": 1}]]"#).unwrap();
+        assert!(w.next_round().unwrap());
+        assert_eq!(w.round(), 1);
+        assert!(w.base_dir().ends_with("round-001"));
+        assert_eq!(w.finished_split().unwrap().len(), 1);
+        assert!(!next_path.exists());
+        assert!(!w.next_round().unwrap());
+
+        fs::remove_dir_all(&runs_dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume_round() {
+        let runs_dir = runs_dir("resume_round");
+        let mut w = WorkDir::new_in(&runs_dir).unwrap();
+        let run_id = w.run_id().to_owned();
+        let next_path = w.merge_path().unwrap().join("next");
+        assert_eq!(WorkDir::resume_in(&runs_dir, &run_id).unwrap().round(), 0);
+
+        fs::create_dir_all(&next_path).unwrap();
+        fs::write(next_path.join("tasks.json"), r#"[[{"meta": 1}]]"#).unwrap();
+        let mut w = WorkDir::resume_in(&runs_dir, &run_id).unwrap();
+        assert!(w.next_round().unwrap());
+        // A round without split results has not started yet.
+        fs::create_dir_all(w.base_dir().parent().unwrap().join("round-002")).unwrap();
+
+        let w = WorkDir::resume_in(&runs_dir, &run_id).unwrap();
+        assert_eq!(w.round(), 1);
+        assert!(w.base_dir().ends_with("round-001"));
+        assert_eq!(w.finished_split().unwrap().len(), 1);
+
+        fs::remove_dir_all(&runs_dir).unwrap();
+    }
+
+    #[test]
+    fn test_place_blob_replaces() {
+        let runs_dir = runs_dir("place_blob_replaces");
+        let mut w = WorkDir::new_in(&runs_dir).unwrap();
+        let from = w.split_output().unwrap().join("dir");
+        let to = w.task_path(0).unwrap().join("in").join("dir");
+        fs::create_dir_all(&from).unwrap();
+        fs::write(from.join("a.bin"), b"new").unwrap();
+        fs::create_dir_all(&to).unwrap();
+        fs::write(to.join("a.bin"), b"old").unwrap();
+        fs::write(to.join("stale.bin"), b"old").unwrap();
+
+        place_blob(&from, &to, true).unwrap();
+        assert_eq!(fs::read(to.join("a.bin")).unwrap(), b"new");
+        assert!(!to.join("stale.bin").exists());
+        place_blob(&from, &to, false).unwrap();
+        assert_eq!(fs::read(to.join("a.bin")).unwrap(), b"new");
+        assert!(!from.exists());
+
+        fs::remove_dir_all(&runs_dir).unwrap();
+    }
+
     #[test]
     fn test_app_dir() {
         eprintln!(
@@ -1,5 +1,5 @@
 #![allow(clippy::unit_arg)]
-use gwr_backend::{rt, Flags};
+use gwr_backend::{rt, Flags, Limits};
 use std::path::Path;
 
 mod runner;
@@ -28,13 +28,21 @@ impl GuBackend {
         })
     }
 
-    pub fn run<E: rt::Engine>(
+    pub fn run<E: rt::Engine + Send + Sync>(
         &self,
         engine: E,
-        _flags: &Flags,
+        flags: &Flags,
         wasm_path: &Path,
         args: &[String],
     ) -> anyhow::Result<()> {
-        runner::run(engine, self.hub_url.clone(), wasm_path, args)
+        runner::run(
+            engine,
+            self.hub_url.clone(),
+            wasm_path,
+            &Limits::from_flags(flags),
+            flags.resume.as_deref(),
+            flags.output_dir.as_deref(),
+            args,
+        )
     }
 }
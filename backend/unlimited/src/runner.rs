@@ -4,16 +4,25 @@ use futures::Async;
 use gu_client::model::envman::{Command, CreateSession, ResourceFormat};
 use gu_client::{r#async as guc, NodeId};
 use gu_wasm_env_api::{EntryPoint, Manifest, MountPoint, RuntimeType};
-use gwr_backend::dispatcher::TaskDef;
-use gwr_backend::{rt, run_local_code, WorkDir};
+use gwr_backend::archive::{archive_path, pack_dir, unpack_dir};
+use gwr_backend::dispatcher::{TaskDef, TaskFile};
+use gwr_backend::{
+    check_app, rt, run_merge_code, run_split_streaming, subtasks_bar, task_log_paths, Limits,
+    WorkDir,
+};
 use serde::Serialize;
+use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::{self, BufWriter, Cursor, Read, Write};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use zip::CompressionMethod;
 
 // 1. Image cache [TODO]
@@ -87,19 +96,32 @@ fn push_image(
 
 struct Work {
     commands: Vec<Command>,
+    /// Position of the `exec` command, whose output is the guest output.
+    exec_idx: usize,
     meta_blob: guc::Blob,
-    outputs: Vec<(guc::Blob, PathBuf)>,
+    /// Output blobs, with a flag set for blob dirs.
+    outputs: Vec<(guc::Blob, PathBuf, bool)>,
     task_path: PathBuf,
     merge_path: PathBuf,
 }
 
+/// Blob dirs travel as tar archives, packed and unpacked by the hub.
+fn resource_format(is_dir: bool) -> ResourceFormat {
+    if is_dir {
+        ResourceFormat::Tar
+    } else {
+        ResourceFormat::Raw
+    }
+}
+
 fn download_blob(
     blob: &guc::Blob,
     destination: &Path,
 ) -> impl Future<Item = (), Error = anyhow::Error> {
     let mut f = OpenOptions::new()
         .write(true)
-        .create_new(true)
+        .create(true)
+        .truncate(true)
         .open(destination)
         .unwrap();
     blob.download()
@@ -111,6 +133,31 @@ fn download_blob(
 }
 
 impl Work {
+    /// Keeps provider output of the subtask next to its results.
+    fn write_logs(&self, r: &Result<Vec<String>, gu_client::error::Error>) -> anyhow::Result<()> {
+        let (stdout_path, stderr_path) = task_log_paths(self.task_path.parent().unwrap());
+        match r {
+            Ok(outputs) => {
+                let mut stderr = String::new();
+                for (idx, output) in outputs.iter().enumerate() {
+                    if idx != self.exec_idx && !output.is_empty() {
+                        stderr.push_str(&format!("[command {}] {}\n", idx, output));
+                    }
+                }
+                fs::write(
+                    stdout_path,
+                    outputs.get(self.exec_idx).map(String::as_str).unwrap_or(""),
+                )?;
+                fs::write(stderr_path, stderr)?;
+            }
+            Err(e) => {
+                fs::write(stdout_path, "")?;
+                fs::write(stderr_path, e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
     fn download_results(&self) -> impl Future<Item = TaskDef, Error = anyhow::Error> {
         let files = if self.outputs.is_empty() {
             futures::future::Either::A(futures::future::ok(()))
@@ -119,7 +166,18 @@ impl Work {
                 futures::future::join_all(
                     self.outputs
                         .iter()
-                        .map(|(blob, output)| download_blob(blob, output))
+                        .map(|(blob, output, is_dir)| {
+                            let (output, is_dir) = (output.clone(), *is_dir);
+                            let tar_path = archive_path(&output);
+                            download_blob(blob, if is_dir { &tar_path } else { &output }).and_then(
+                                move |()| {
+                                    if is_dir {
+                                        unpack_dir(&tar_path, &output)?;
+                                    }
+                                    Ok(())
+                                },
+                            )
+                        })
                         .collect::<Vec<_>>(),
                 )
                 .and_then(|_| Ok(())),
@@ -135,13 +193,22 @@ impl Work {
                 futures::future::ok::<_, anyhow::Error>(v)
             })
             .and_then(|d| -> Result<TaskDef, _> {
-                serde_json::from_slice(d.as_ref()).map_err(anyhow::Error::msg)
+                serde_json::from_slice::<TaskFile<TaskDef>>(d.as_ref())
+                    .map(|file| file.data)
+                    .map_err(anyhow::Error::msg)
             });
 
         let task_path = self.task_path.clone();
         let merge_path = self.merge_path.clone();
 
         Future::join(files, task_def).and_then(move |(_, task_def)| {
+            // Corrupted downloads fail the work, which is then queued again.
+            task_def.verify_blobs(&task_path)?;
+            // Written last, so a resumed run only trusts tasks with all outputs in place.
+            fs::write(
+                task_path.join("task.json"),
+                serde_json::to_vec_pretty(&task_def)?,
+            )?;
             task_def
                 .rebase_to(&task_path, &merge_path)
                 .map_err(anyhow::Error::from)
@@ -168,27 +235,46 @@ impl WorkPeerState {
 
 type ReplyRef = oneshot::Sender<Result<TaskDef, anyhow::Error>>;
 
+/// How often the manager checks the deadline of the run.
+const DEADLINE_POLL: Duration = Duration::from_secs(1);
+
 struct WorkManager {
     session: guc::HubSessionRef,
     deployment_desc: CreateSession,
     peers: HashMap<NodeId, WorkPeerState>,
     todo: VecDeque<(Arc<Work>, ReplyRef)>,
+    limits: Limits,
 }
 
 impl Actor for WorkManager {
     type Context = Context<Self>;
 
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.limits.deadline().is_none() {
+            return;
+        }
+        ctx.run_interval(DEADLINE_POLL, |act, ctx| {
+            if act.limits.check_deadline().is_err() {
+                // Replies of running work are dropped with the manager.
+                for (_, reply) in act.todo.drain(..) {
+                    let _ = reply.send(Err(anyhow::anyhow!("run timed out")));
+                }
+                ctx.stop();
+            }
+        });
+    }
+
     fn stopped(&mut self, ctx: &mut Self::Context) {
         let _ = self.session.clone().into_inner();
         for (node_id, s) in &self.peers {
             match s {
-                WorkPeerState::Added => eprintln!("{:?} - added", node_id),
-                WorkPeerState::Pending(_, _) => eprintln!("{:?} - pending", node_id),
-                WorkPeerState::Work(_) => eprintln!("{:?} - working", node_id),
-                WorkPeerState::Backoff => eprintln!("{:?} - error", node_id),
+                WorkPeerState::Added => log::debug!("{:?} - added", node_id),
+                WorkPeerState::Pending(_, _) => log::debug!("{:?} - pending", node_id),
+                WorkPeerState::Work(_) => log::debug!("{:?} - working", node_id),
+                WorkPeerState::Backoff => log::debug!("{:?} - error", node_id),
             }
         }
-        eprintln!("work done: {} task pending", self.todo.len());
+        log::info!("work done: {} task pending", self.todo.len());
         ctx.wait(
             self.session
                 .deref()
@@ -282,28 +368,50 @@ impl WorkManager {
                     .then(
                         move |r: Result<Vec<String>, gu_client::error::Error>, act, ctx| {
                             log::debug!("deployment resolved: {:?}", r);
+                            if let Err(e) = work.write_logs(&r) {
+                                log::error!("failed to write subtask logs: {}", e);
+                            }
                             ctx.spawn(deployment.delete().then(|_| Ok(())).into_actor(act));
                             act.release_node(node_id, ctx);
-                            if let Err(e) = r {
-                                let _ = reply.send(Err(anyhow::Error::msg(e)));
-                                return fut::Either::B(fut::ok(()));
-                            }
-
-                            fut::Either::A(
-                                work.download_results()
-                                    .and_then(move |task_def| {
+                            let results = match r {
+                                Ok(_) => futures::future::Either::A(work.download_results()),
+                                Err(e) => futures::future::Either::B(futures::future::err(
+                                    anyhow::Error::msg(e),
+                                )),
+                            };
+                            results.into_actor(act).then(move |r, act, ctx| {
+                                match r {
+                                    Ok(task_def) => {
                                         let _ = reply.send(Ok(task_def));
-                                        Ok(())
-                                    })
-                                    .into_actor(act),
-                            )
+                                    }
+                                    Err(e) => act.retry(work, reply, e, ctx),
+                                }
+                                fut::ok(())
+                            })
                         },
-                    )
-                    .map_err(|e, _, _| log::error!("download results error: {}", e)),
+                    ),
             );
         }
     }
 
+    /// Queues failed work again, unless the run is out of time.
+    fn retry(
+        &mut self,
+        work: Arc<Work>,
+        reply: ReplyRef,
+        error: anyhow::Error,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        log::error!("fail to process subtask: {}", error);
+        if let Err(e) = self.limits.check_deadline() {
+            let _ = reply.send(Err(e));
+            return;
+        }
+        log::info!("retry");
+        self.todo.push_back((work, reply));
+        self.schedule_tasks(ctx);
+    }
+
     fn backoff_node(&mut self, node_id: NodeId) {
         log::info!("backoff node: {:?}", node_id);
         if let Some(s) = self.peers.insert(node_id, WorkPeerState::Backoff) {
@@ -370,6 +478,7 @@ impl WorkManager {
         session: guc::HubSessionRef,
         peers: Vec<NodeId>,
         deployment_desc: CreateSession,
+        limits: Limits,
     ) -> Addr<WorkManager> {
         let peers = peers
             .into_iter()
@@ -380,11 +489,164 @@ impl WorkManager {
             peers,
             deployment_desc,
             todo: Default::default(),
+            limits,
         }
         .start()
     }
 }
 
+/// Uploads the inputs of a subtask and prepares its commands.
+///
+/// Shared blobs are uploaded by the first subtask using them and referenced by the others.
+#[allow(clippy::too_many_arguments)]
+fn task_work(
+    session: &guc::HubSessionRef,
+    idx: usize,
+    task: TaskDef,
+    task_dir: PathBuf,
+    split_path: &Path,
+    merge_path: &Path,
+    protocol: u32,
+    shared_uris: &Rc<RefCell<HashMap<String, String>>>,
+) -> impl Future<Item = Work, Error = anyhow::Error> {
+    let task_desc = TaskFile {
+        version: protocol,
+        data: task.clone(),
+    };
+    let input_data_iter = task
+        .blob_refs()
+        .map(|blob| (blob, false))
+        .chain(task.blob_dirs().map(|blob| (blob, true)))
+        .map(|(blob, is_dir)| {
+            let file_path = format!("/in/{}", blob.path);
+            if let Some(uri) = shared_uris.borrow().get(&blob.path) {
+                return futures::future::Either::A(futures::future::ok(Command::DownloadFile {
+                    uri: String::clone(uri),
+                    file_path,
+                    format: resource_format(is_dir),
+                }));
+            }
+            let mut blob_path = split_path.join(&blob.path);
+            if is_dir {
+                blob_path = archive_path(&blob_path);
+            }
+            let s = file_stream(&blob_path);
+            let shared = blob
+                .shared
+                .then(|| (shared_uris.clone(), blob.path.clone()));
+            futures::future::Either::B(session.new_blob().and_then(move |b| {
+                if shared.is_some() {
+                    log::debug!("new shared blob: {}", b.id());
+                } else {
+                    log::debug!("new blob: {}", b.id());
+                }
+                b.upload_from_stream(s).and_then(move |()| {
+                    if let Some((shared_uris, blob_id)) = shared {
+                        shared_uris.borrow_mut().insert(blob_id, b.uri());
+                    }
+                    Ok(Command::DownloadFile {
+                        uri: b.uri(),
+                        file_path,
+                        format: resource_format(is_dir),
+                    })
+                })
+            }))
+        })
+        .collect::<Vec<_>>();
+    let input_data = futures::future::join_all(input_data_iter);
+
+    let input_meta = session.new_blob().and_then(move |b| {
+        b.upload_from_stream(json_stream(&task_desc))
+            .and_then(move |()| {
+                Ok(Command::DownloadFile {
+                    uri: b.uri(),
+                    file_path: "/in/task.json".to_string(),
+                    format: ResourceFormat::Raw,
+                })
+            })
+    });
+    let output_data = futures::future::join_all(
+        task.outputs()
+            .into_iter()
+            .map(|blob_id| (blob_id, false))
+            .chain(task.output_dirs().map(|blob_id| (blob_id, true)))
+            .map(|(blob_id, is_dir)| {
+                let file_path = format!("/out/{}", blob_id);
+                let output_path = task_dir.join(blob_id);
+                session.new_blob().and_then(move |b| {
+                    log::debug!("new output {} {}", file_path, b.id());
+                    Ok((
+                        Command::UploadFile {
+                            uri: b.uri(),
+                            file_path,
+                            format: resource_format(is_dir),
+                        },
+                        b,
+                        output_path,
+                        is_dir,
+                    ))
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+
+    let task_dir_ref = task_dir;
+    let merge_path_ref = merge_path.to_owned();
+
+    let output_meta = session.new_blob().and_then(move |b| {
+        Ok((
+            Command::UploadFile {
+                uri: b.uri(),
+                file_path: "/out/task.json".to_string(),
+                format: ResourceFormat::Raw,
+            },
+            b,
+            task_dir_ref,
+            merge_path_ref,
+        ))
+    });
+
+    input_meta
+        .join4(input_data, output_meta, output_data)
+        .and_then(
+            move |(
+                in_meta,
+                in_data,
+                (out_meta, out_meta_blob, task_path, merge_path),
+                out_data,
+            )| {
+                let mut commands = Vec::new();
+                let mut downloads = Vec::new();
+                commands.push(in_meta);
+                commands.extend(in_data);
+                let exec_idx = commands.len();
+                commands.push(Command::Exec {
+                    executable: "job".to_string(),
+                    args: vec![
+                        "exec".to_string(),
+                        "/in/task.json".to_string(),
+                        "/out/task.json".to_string(),
+                        idx.to_string(),
+                    ],
+                });
+                commands.push(out_meta);
+                for (command, blob, out_path, is_dir) in out_data {
+                    commands.push(command);
+                    downloads.push((blob, out_path, is_dir));
+                }
+                Ok(Work {
+                    commands,
+                    exec_idx,
+                    meta_blob: out_meta_blob,
+                    outputs: downloads,
+                    task_path,
+                    merge_path,
+                })
+            },
+        )
+        .map_err(anyhow::Error::msg)
+}
+
 fn file_stream(f: &Path) -> impl Stream<Item = bytes::Bytes, Error = io::Error> {
     let mut inf = fs::OpenOptions::new().read(true).open(f).unwrap();
     let mut buf = bytes::BytesMut::with_capacity(40960);
@@ -412,15 +674,19 @@ fn json_stream<T: Serialize>(object: &T) -> impl Stream<Item = bytes::Bytes, Err
     futures::stream::once(bytes)
 }
 
-pub fn run<E: rt::Engine>(
+pub fn run<E: rt::Engine + Send + Sync>(
     engine: E,
     hub_addr: String,
     wasm_path: &Path,
+    limits: &Limits,
+    resume: Option<&str>,
+    output_dir: Option<&Path>,
     args: &[String],
 ) -> anyhow::Result<()> {
     {
         let mut sys = System::new("GU-wasm -runner");
-        let mut w = WorkDir::new("gu")?;
+        let mut w = WorkDir::for_run("gu", resume)?;
+        w.set_app_protocol(check_app(engine.clone(), limits, wasm_path)?);
 
         let js_path = wasm_path.with_extension("js");
 
@@ -432,231 +698,290 @@ pub fn run<E: rt::Engine>(
 
         let hub_url: Arc<str> = format!("http://{}", hub_addr).into();
 
-        let output_path = w.split_output()?;
-        {
-            let mut split_args = Vec::new();
-            split_args.push("split".to_owned());
-            split_args.push("/task_dir/".to_owned());
-            split_args.extend(args.iter().cloned());
-            run_local_code(engine.clone(), wasm_path, &output_path, split_args)?;
-        }
-
-        let tasks_path = output_path.join("tasks.json");
-
-        eprintln!("reading: {}", tasks_path.display());
-        let tasks: Vec<TaskDef> =
-            serde_json::from_reader(fs::OpenOptions::new().read(true).open(tasks_path)?)?;
-
-        let merge_path = w.merge_path()?;
-        let output_file = merge_path.join("tasks.json");
-        let merge_path_ref = merge_path.clone();
-
-        let image_fut = push_image(hub_url, image)
-            .map_err(anyhow::Error::msg)
-            .and_then(|(image_url, image_hash)| {
-                eprintln!("got image: {}", image_url);
-                let c = gu_client::r#async::HubConnection::from_addr(hub_addr).unwrap();
-                let session = c
-                    .new_session(gu_client::model::session::HubSessionSpec {
-                        expires: None,
-                        allocation: Default::default(),
-                        name: Some(format!("work for {}", wasm_path.display())),
-                        tags: vec!["gu:wasm".to_string(), "gu:wasm:runner".to_string()]
-                            .into_iter()
-                            .collect(),
-                    })
-                    .map_err(anyhow::Error::msg);
-                let peers = c.list_peers().map_err(anyhow::Error::msg);
-
-                session.join4(
-                    peers,
-                    futures::future::ok(image_url),
-                    futures::future::ok(image_hash),
-                )
-            });
+        loop {
+            let split_path = w.split_output()?;
+            let finished_split = w.finished_split();
+            if finished_split.is_some() && w.round() == 0 {
+                log::info!("reusing split from previous attempt");
+            }
+            // Without a finished split, the first wave runs work items as the split streams them.
+            let mut streaming = finished_split.is_none();
+            let mut tasks = finished_split.unwrap_or_default();
+
+            let merge_path = w.merge_path()?;
+            let output_file = merge_path.join("tasks.json");
+
+            let mut outputs = Vec::new();
+            let mut spawned = Vec::new();
+            // Work items spawned by a wave of tasks run in the next wave.
+            let mut wave_start = 0;
+            while streaming || wave_start < tasks.len() {
+                limits.check_deadline()?;
+                let hub_addr = hub_addr.clone();
+                let output_path = split_path.clone();
+                let merge_path_ref = merge_path.clone();
+
+                let (sender, items) = futures::sync::mpsc::unbounded();
+                let mut reused = Vec::new();
+                let mut pending = 0;
+                if !streaming {
+                    for (idx, task) in (wave_start..).zip(tasks[wave_start..].iter().cloned()) {
+                        let task_output_path = w.task_path(idx)?.join("out");
+                        fs::create_dir_all(&task_output_path)?;
+                        match w.finished_task(idx) {
+                            Some(task_def) => reused
+                                .push((idx, task_def.rebase_to(&task_output_path, &merge_path)?)),
+                            None => {
+                                let _ = sender.unbounded_send((idx, task, task_output_path));
+                                pending += 1;
+                            }
+                        }
+                    }
+                    if !reused.is_empty() {
+                        log::info!(
+                            "skipping {} tasks finished by previous attempt",
+                            reused.len()
+                        );
+                    }
+                }
+                // Blob dirs are packed by the first work item using them.
+                let archives = Rc::new(RefCell::new(Vec::new()));
+                let work_archives = archives.clone();
 
-        let work = image_fut
-            .and_then(|(session, peers, image_url, image_hash)| {
-                session
-                    .add_peers(peers.map(|n| n.node_id))
+                let image_fut = push_image(hub_url.clone(), image.clone())
                     .map_err(anyhow::Error::msg)
-                    .and_then(move |nodes| Ok((session, nodes, image_url, image_hash)))
-            })
-            .from_err()
-            .and_then(
-                move |(session, nodes, image_url, image_hash): (guc::HubSessionRef, _, _, _)| {
-                    let session_for_update = session.clone();
-                    let merge_path = merge_path_ref.clone();
-
-                    futures::future::join_all(
-                        tasks
-                            .into_iter()
-                            .map(move |task| {
-                                let task_dir = w.new_task().unwrap();
-
-                                let task_desc = task.clone();
-                                let input_data_iter = task
-                                    .blobs()
+                    .and_then(|(image_url, image_hash)| {
+                        log::debug!("got image: {}", image_url);
+                        let c = gu_client::r#async::HubConnection::from_addr(hub_addr).unwrap();
+                        let session = c
+                            .new_session(gu_client::model::session::HubSessionSpec {
+                                expires: None,
+                                allocation: Default::default(),
+                                name: Some(format!("work for {}", wasm_path.display())),
+                                tags: vec!["gu:wasm".to_string(), "gu:wasm:runner".to_string()]
                                     .into_iter()
-                                    .map(|blob_id| {
-                                        let blob_path = output_path.join(blob_id);
-                                        let s = file_stream(&blob_path);
-                                        let file_path = format!("/in/{}", blob_id);
-                                        session.new_blob().and_then(move |b| {
-                                            eprintln!("new blob: {}", b.id());
-                                            b.upload_from_stream(s).and_then(move |()| {
-                                                Ok(Command::DownloadFile {
-                                                    uri: b.uri(),
-                                                    file_path,
-                                                    format: ResourceFormat::Raw,
-                                                })
-                                            })
-                                        })
-                                    })
-                                    .collect::<Vec<_>>();
-                                let input_data = futures::future::join_all(input_data_iter);
-
-                                let input_meta = session.new_blob().and_then(move |b| {
-                                    b.upload_from_stream(json_stream(&task_desc)).and_then(
-                                        move |()| {
-                                            Ok(Command::DownloadFile {
-                                                uri: b.uri(),
-                                                file_path: "/in/task.json".to_string(),
-                                                format: ResourceFormat::Raw,
-                                            })
-                                        },
-                                    )
-                                });
-                                let output_data = futures::future::join_all(
-                                    task.outputs()
-                                        .into_iter()
-                                        .map(|blob_id| {
-                                            let file_path = format!("/out/{}", blob_id);
-                                            let output_path = task_dir.join(blob_id);
-                                            session.new_blob().and_then(move |b| {
-                                                log::debug!("new output {} {}", file_path, b.id());
-                                                Ok((
-                                                    Command::UploadFile {
-                                                        uri: b.uri(),
-                                                        file_path,
-                                                        format: ResourceFormat::Raw,
-                                                    },
-                                                    b,
-                                                    output_path,
-                                                ))
-                                            })
-                                        })
-                                        .collect::<Vec<_>>(),
-                                );
-
-                                let task_dir_ref = task_dir;
-                                let merge_path_ref = merge_path.clone();
-
-                                let output_meta = session.new_blob().and_then(move |b| {
-                                    Ok((
-                                        Command::UploadFile {
-                                            uri: b.uri(),
-                                            file_path: "/out/task.json".to_string(),
-                                            format: ResourceFormat::Raw,
-                                        },
-                                        b,
-                                        task_dir_ref,
-                                        merge_path_ref,
-                                    ))
-                                });
-
-                                input_meta
-                                    .join4(input_data, output_meta, output_data)
-                                    .and_then(
-                                        |(
-                                            in_meta,
-                                            in_data,
-                                            (out_meta, out_meta_blob, task_path, merge_path),
-                                            out_data,
-                                        )| {
-                                            let mut commands = Vec::new();
-                                            let mut downloads = Vec::new();
-                                            commands.push(in_meta);
-                                            commands.extend(in_data);
-                                            commands.push(Command::Exec {
-                                                executable: "job".to_string(),
-                                                args: vec![
-                                                    "exec".to_string(),
-                                                    "/in/task.json".to_string(),
-                                                    "/out/task.json".to_string(),
-                                                ],
-                                            });
-                                            commands.push(out_meta);
-                                            for (command, blob, out_path) in out_data {
-                                                commands.push(command);
-                                                downloads.push((blob, out_path));
-                                            }
-                                            Ok(Work {
-                                                commands,
-                                                meta_blob: out_meta_blob,
-                                                outputs: downloads,
-                                                task_path,
-                                                merge_path,
-                                            })
-                                        },
-                                    )
+                                    .collect(),
                             })
-                            .collect::<Vec<_>>(),
-                    )
-                    .map_err(anyhow::Error::msg)
-                    .and_then(move |r| {
-                        log::info!("running code: {} tasks", r.len());
-                        let session = session_for_update.clone();
-                        let image = gu_client::model::envman::Image {
-                            url: image_url,
-                            hash: image_hash,
-                        };
-                        let deployment_desc = CreateSession {
-                            env_type: "wasm".to_string(),
-                            image,
-                            name: "".to_string(),
-                            tags: vec![],
-                            note: None,
-                            options: (),
-                        };
-
-                        let w = WorkManager::new(session, nodes, deployment_desc);
-                        let we = w.clone();
-                        futures::future::join_all(
-                            r.into_iter()
-                                .map(move |work| w.send(RunWork(work)).flatten()),
+                            .map_err(anyhow::Error::msg);
+                        let peers = c.list_peers().map_err(anyhow::Error::msg);
+
+                        session.join4(
+                            peers,
+                            futures::future::ok(image_url),
+                            futures::future::ok(image_hash),
                         )
-                        .and_then(move |tasks| Ok((tasks, we)))
+                    });
+
+                let protocol = w.app_protocol();
+                // Hub sessions do not relay progress files, only finished subtasks are shown.
+                let bar = subtasks_bar(0);
+                let work_bar = bar.clone();
+                let work = image_fut
+                    .and_then(|(session, peers, image_url, image_hash)| {
+                        session
+                            .add_peers(peers.map(|n| n.node_id))
+                            .map_err(anyhow::Error::msg)
+                            .and_then(move |nodes| Ok((session, nodes, image_url, image_hash)))
                     })
-                },
-            )
-            .and_then(|(tasks, w)| w.send(StopManager).then(|_| Ok(tasks)))
-            .map_err(|e| {
-                log::error!("fail: {:?}", e);
-                e
-            });
+                    .from_err()
+                    .and_then(
+                        move |(session, nodes, image_url, image_hash): (
+                            guc::HubSessionRef,
+                            _,
+                            _,
+                            _,
+                        )| {
+                            let merge_path = merge_path_ref.clone();
+                            let image = gu_client::model::envman::Image {
+                                url: image_url,
+                                hash: image_hash,
+                            };
+                            let deployment_desc = CreateSession {
+                                env_type: "wasm".to_string(),
+                                image,
+                                name: "".to_string(),
+                                tags: vec![],
+                                note: None,
+                                options: (),
+                            };
+                            let manager = WorkManager::new(
+                                session.clone(),
+                                nodes,
+                                deployment_desc,
+                                limits.clone(),
+                            );
+                            let stop_manager = manager.clone();
+                            let pack_path = output_path.clone();
+                            let shared_uris = Rc::new(RefCell::new(HashMap::new()));
+
+                            items
+                                .map_err(|()| anyhow::anyhow!("work items channel failed"))
+                                .and_then(
+                                    move |(idx, task, task_dir): (usize, TaskDef, PathBuf)| {
+                                        let mut archives = work_archives.borrow_mut();
+                                        for blob in task.blob_dirs() {
+                                            let blob_path = pack_path.join(&blob.path);
+                                            if !archives.contains(&archive_path(&blob_path)) {
+                                                archives.push(pack_dir(&blob_path)?);
+                                            }
+                                        }
+                                        Ok::<_, anyhow::Error>((idx, task, task_dir))
+                                    },
+                                )
+                                .and_then(move |(idx, task, task_dir)| {
+                                    task_work(
+                                        &session,
+                                        idx,
+                                        task,
+                                        task_dir,
+                                        &output_path,
+                                        &merge_path,
+                                        protocol,
+                                        &shared_uris,
+                                    )
+                                    .map(move |work| (idx, work))
+                                })
+                                .map(move |(idx, work)| {
+                                    work_bar.inc_length(1);
+                                    let bar = work_bar.clone();
+                                    manager.send(RunWork(work)).flatten().map(move |output| {
+                                        bar.inc(1);
+                                        (idx, output)
+                                    })
+                                })
+                                .buffer_unordered(usize::MAX)
+                                .collect()
+                                .and_then(move |results| {
+                                    stop_manager.send(StopManager).then(|_| Ok(results))
+                                })
+                        },
+                    )
+                    .map_err(|e| {
+                        log::error!("fail: {:?}", e);
+                        e
+                    });
+
+                guc::disable_release();
+                let cancelled = AtomicBool::new(false);
+                let (split, results) = thread::scope(|s| {
+                    let split = streaming.then(|| {
+                        let (split_engine, w, split_path, cancelled) =
+                            (engine.clone(), &mut w, &split_path, &cancelled);
+                        s.spawn(move || {
+                            run_split_streaming(
+                                split_engine,
+                                limits,
+                                wasm_path,
+                                args,
+                                split_path,
+                                cancelled,
+                                &mut |idx, task| {
+                                    let task_output_path = w.task_path(idx)?.join("out");
+                                    fs::create_dir_all(&task_output_path)?;
+                                    let _ = sender.unbounded_send((
+                                        idx,
+                                        task.clone(),
+                                        task_output_path,
+                                    ));
+                                    Ok(())
+                                },
+                            )
+                        })
+                    });
+                    let results = if streaming || pending > 0 {
+                        sys.block_on(work)
+                    } else {
+                        Ok(Vec::new())
+                    };
+                    if results.is_err() {
+                        cancelled.store(true, Ordering::SeqCst);
+                    }
+                    (split.map(|split| split.join().unwrap()), results)
+                });
+                bar.finish_and_clear();
+                for tar_path in archives.borrow().iter() {
+                    let _ = fs::remove_file(tar_path);
+                }
+                if let Some(split) = split {
+                    tasks = split?;
+                    streaming = false;
+                }
+                limits.check_deadline()?;
+                let results = results?;
 
-        guc::disable_release();
-        let tasks = sys.block_on(work)?;
-
-        serde_json::to_writer_pretty(
-            BufWriter::new(
-                OpenOptions::new()
-                    .write(true)
-                    .truncate(true)
-                    .create_new(true)
-                    .open(&output_file)?,
-            ),
-            &tasks,
-        )?;
-        {
-            let mut merge_args = Vec::new();
-            merge_args.push("merge".to_owned());
-            merge_args.push("/task_dir/split/tasks.json".to_owned());
-            merge_args.push("/task_dir/merge/tasks.json".to_owned());
-            merge_args.push("--".to_owned());
-            merge_args.extend(args.iter().cloned());
-            run_local_code(engine, wasm_path, merge_path.parent().unwrap(), merge_args)?;
+                let wave_end = tasks.len();
+                outputs.resize(wave_end, None);
+                for (idx, task_def) in reused.into_iter().chain(results) {
+                    outputs[idx] = Some(task_def);
+                }
+                // Spawned tasks are numbered in split order, regardless of completion order.
+                for idx in wave_start..wave_end {
+                    let task_output_path = w.task_path(idx)?.join("out");
+                    let output = outputs[idx]
+                        .as_ref()
+                        .ok_or_else(|| anyhow::anyhow!("missing task output"))?;
+                    let children = w.spawned_tasks(&task_output_path, output, tasks.len())?;
+                    spawned.push((tasks.len()..tasks.len() + children.len()).collect::<Vec<_>>());
+                    tasks.extend(children);
+                }
+                if tasks.len() > wave_end {
+                    log::info!("tasks spawned {} more", tasks.len() - wave_end);
+                }
+                wave_start = wave_end;
+            }
+
+            let inputs = tasks
+                .into_iter()
+                .map(|task| task.rebase_to(&split_path, &merge_path))
+                .collect::<Result<Vec<_>, _>>()?;
+            serde_json::to_writer_pretty(
+                BufWriter::new(
+                    OpenOptions::new()
+                        .write(true)
+                        .truncate(true)
+                        .create(true)
+                        .open(merge_path.join("tasks_input.json"))?,
+                ),
+                &w.task_file(&inputs),
+            )?;
+            let tasks = outputs
+                .into_iter()
+                .zip(spawned)
+                .map(|(output, children)| {
+                    output
+                        .map(|output| output.link_spawned(children))
+                        .ok_or_else(|| anyhow::anyhow!("missing task output"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            serde_json::to_writer_pretty(
+                BufWriter::new(
+                    OpenOptions::new()
+                        .write(true)
+                        .truncate(true)
+                        .create(true)
+                        .open(&output_file)?,
+                ),
+                &w.task_file(&tasks),
+            )?;
+            {
+                let mut merge_args = Vec::new();
+                merge_args.push("merge".to_owned());
+                merge_args.push("/task_dir/merge/tasks_input.json".to_owned());
+                merge_args.push("/task_dir/merge/tasks.json".to_owned());
+                merge_args.push("--".to_owned());
+                merge_args.extend(args.iter().cloned());
+                run_merge_code(
+                    engine.clone(),
+                    limits,
+                    wasm_path,
+                    &merge_path,
+                    merge_args,
+                    output_dir,
+                )?;
+            }
+            if !w.next_round()? {
+                break;
+            }
         }
     }
 
@@ -2,6 +2,7 @@
 
 use crate::{Demand, YagnaEngine};
 use chrono::{Datelike, Timelike, Utc};
+use gwr_backend::rt::ResourceLimits;
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::fs;
@@ -51,6 +52,14 @@ impl MountPoint {
     }
 }
 
+/// Provider memory needed for the given guest limits, in GiB.
+fn min_mem_gib(limits: &ResourceLimits) -> f64 {
+    match limits.max_memory {
+        Some(max_memory) => (max_memory as f64 / (1u64 << 30) as f64).max(0.5),
+        None => 0.5,
+    }
+}
+
 fn zip_time_from_path(p: &Path) -> anyhow::Result<zip::DateTime> {
     let mtime: chrono::DateTime<Utc> = p.metadata()?.modified()?.into();
 
@@ -110,6 +119,7 @@ gwr_backend::for_wasmtime! {
             node_name: &str,
             wasm_url: &str,
             timeout: Duration,
+            limits: &ResourceLimits,
             subnet: Option<&String>
         ) -> anyhow::Result<Demand> {
             let expiration = Utc::now()
@@ -126,13 +136,15 @@ gwr_backend::for_wasmtime! {
 
             Ok(Demand {
                 properties,
-                constraints: r#"(&
-                (golem.inf.mem.gib>0.5)
+                constraints: format!(
+                    r#"(&
+                (golem.inf.mem.gib>{})
                 (golem.inf.storage.gib>1)
                 (golem.com.pricing.model=linear)
                 (golem.runtime.name=wasmtime)
-            )"#
-                    .to_string(),
+            )"#,
+                    min_mem_gib(limits)
+                ),
                 demand_id: Default::default(),
                 requestor_id: Default::default(),
             })
@@ -191,6 +203,7 @@ gwr_backend::for_spwasm! {
             node_name: &str,
             wasm_url: &str,
             timeout: Duration,
+            limits: &ResourceLimits,
             subnet: Option<&String>
         ) -> anyhow::Result<Demand> {
             let expiration = Utc::now()
@@ -207,13 +220,15 @@ gwr_backend::for_spwasm! {
 
             Ok(Demand {
                 properties,
-                constraints: r#"(&
-                (golem.inf.mem.gib>0.5)
+                constraints: format!(
+                    r#"(&
+                (golem.inf.mem.gib>{})
                 (golem.inf.storage.gib>1)
                 (golem.com.pricing.model=linear)
                 (golem.runtime.name=emscripten)
-            )"#
-                    .to_string(),
+            )"#,
+                    min_mem_gib(limits)
+                ),
                 demand_id: Default::default(),
                 requestor_id: Default::default(),
             })
@@ -1,7 +1,8 @@
 #![allow(clippy::unit_arg)]
 use gwr_backend::rt::Engine;
-use gwr_backend::Flags;
+use gwr_backend::{Flags, Limits};
 use std::path::Path;
+use std::time::Duration;
 use url::Url;
 pub use ya_client::model::market::Demand;
 
@@ -10,6 +11,9 @@ mod negotiator;
 mod runner;
 mod storage;
 
+/// Expiration of demands of runs started without `--timeout`.
+const DEMAND_TIMEOUT: Duration = Duration::from_secs(3 * 60 * 60);
+
 pub trait YagnaEngine: Engine {
     fn build_image(&self, wasm_path: &Path) -> anyhow::Result<Vec<u8>>;
 
@@ -18,6 +22,7 @@ pub trait YagnaEngine: Engine {
         node_name: &str,
         wasm_url: &str,
         timeout: std::time::Duration,
+        limits: &gwr_backend::rt::ResourceLimits,
         subnet: Option<&String>,
     ) -> anyhow::Result<Demand>;
 }
@@ -62,7 +67,7 @@ impl YagnaBackend {
         })
     }
 
-    pub fn run<E: YagnaEngine + 'static>(
+    pub fn run<E: YagnaEngine + Send + Sync + 'static>(
         &self,
         engine: E,
         flags: &Flags,
@@ -75,7 +80,10 @@ impl YagnaBackend {
             self.subnet.clone(),
             engine,
             wasm_path,
-            flags.timeout.into(),
+            flags.timeout.map_or(DEMAND_TIMEOUT, Into::into),
+            &Limits::from_flags(flags),
+            flags.resume.as_deref(),
+            flags.output_dir.as_deref(),
             args,
         )
     }
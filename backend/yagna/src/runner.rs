@@ -5,20 +5,22 @@ use actix_http::httpmessage::HttpMessage;
 use awc::error::WsClientError;
 use bigdecimal::BigDecimal;
 use chrono::{DateTime, Datelike, Timelike, Utc};
-use futures::channel::oneshot;
+use futures::channel::{mpsc, oneshot};
 use futures::prelude::*;
 use futures::TryFutureExt;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use sha3::digest::Digest;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::{Cursor, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering::AcqRel;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use ya_client::market::MarketRequestorApi;
 use ya_client::model;
@@ -31,7 +33,12 @@ use zip::CompressionMethod;
 use super::negotiator::*;
 use super::storage::{DistSlot, DistStorage};
 use crate::YagnaEngine;
-use gwr_backend::{dispatcher::TaskDef, rt::Engine, run_local_code, WorkDir};
+use gwr_backend::{
+    check_app,
+    dispatcher::{TaskDef, TaskFile},
+    rt::Engine,
+    run_merge_code, run_split_streaming, subtasks_bar, task_log_paths, Limits, WorkDir,
+};
 
 async fn push_image(
     hub_url: Arc<str>,
@@ -57,10 +64,14 @@ async fn push_image(
 
 struct PaymentManager {
     payment_api: ya_client::payment::requestor::PaymentRequestorApi,
-    allocation_id: String,
+    /// Allocations made as subtasks arrive, with the amount left in each.
+    /// Invoices wait for the first one.
+    allocations: Vec<(String, BigDecimal)>,
     total_amount: BigDecimal,
     amount_paid: BigDecimal,
     valid_agreements: HashSet<String>,
+    /// Agreements whose results failed to download or verify.
+    rejected_agreements: HashSet<String>,
     last_debit_note_event: DateTime<Utc>,
     last_invoice_event: DateTime<Utc>,
 }
@@ -105,6 +116,12 @@ impl PaymentManager {
     }
 
     fn update_invoices(&mut self, ctx: &mut <PaymentManager as Actor>::Context) {
+        if self.allocations.is_empty() {
+            ctx.run_later(Duration::from_secs(10), |this, ctx| {
+                this.update_invoices(ctx)
+            });
+            return;
+        }
         let mut ts = self.last_invoice_event;
         let api = self.payment_api.clone();
 
@@ -140,9 +157,15 @@ impl PaymentManager {
                                     invoice.issuer_id
                                 );
                                 this.amount_paid += invoice.amount.clone();
+                                let allocation = this
+                                    .allocations
+                                    .iter_mut()
+                                    .max_by(|a, b| a.1.cmp(&b.1))
+                                    .expect("invoices wait for an allocation");
+                                allocation.1 -= invoice.amount.clone();
                                 let acceptance = model::payment::Acceptance {
                                     total_amount_accepted: invoice.amount.clone(),
-                                    allocation_id: this.allocation_id.clone(),
+                                    allocation_id: allocation.0.clone(),
                                 };
                                 Arbiter::spawn(async move {
                                     if let Err(e) =
@@ -151,6 +174,19 @@ impl PaymentManager {
                                         log::error!("invoice {} accept error: {}", invoice_id, e)
                                     }
                                 });
+                            } else if this.rejected_agreements.remove(&invoice.agreement_id) {
+                                let invoice_id = invoice.invoice_id;
+
+                                let spec = model::payment::Rejection {
+                                    rejection_reason: model::payment::RejectionReason::BadService,
+                                    total_amount_accepted: 0.into(),
+                                    message: Some("results failed verification".to_string()),
+                                };
+                                Arbiter::spawn(async move {
+                                    if let Err(e) = api.reject_invoice(&invoice_id, &spec).await {
+                                        log::error!("invoice: {} reject error: {}", invoice_id, e);
+                                    }
+                                });
                             } else {
                                 let invoice_id = invoice.invoice_id;
 
@@ -200,6 +236,43 @@ impl Handler<AcceptAgreement> for PaymentManager {
     }
 }
 
+/// Marks an agreement whose results were not delivered intact, so its
+/// invoice is rejected.
+struct RejectAgreement {
+    agreement_id: String,
+}
+
+impl Message for RejectAgreement {
+    type Result = anyhow::Result<()>;
+}
+
+impl Handler<RejectAgreement> for PaymentManager {
+    type Result = anyhow::Result<()>;
+
+    fn handle(&mut self, msg: RejectAgreement, ctx: &mut Self::Context) -> Self::Result {
+        self.rejected_agreements.insert(msg.agreement_id);
+        Ok(())
+    }
+}
+
+struct AddAllocation {
+    allocation_id: String,
+    total_amount: BigDecimal,
+}
+
+impl Message for AddAllocation {
+    type Result = ();
+}
+
+impl Handler<AddAllocation> for PaymentManager {
+    type Result = ();
+
+    fn handle(&mut self, msg: AddAllocation, ctx: &mut Self::Context) -> Self::Result {
+        self.total_amount += msg.total_amount.clone();
+        self.allocations.push((msg.allocation_id, msg.total_amount));
+    }
+}
+
 struct GetPending;
 
 impl Message for GetPending {
@@ -225,11 +298,16 @@ impl Handler<ReleaseAllocation> for PaymentManager {
 
     fn handle(&mut self, msg: ReleaseAllocation, ctx: &mut Self::Context) -> Self::Result {
         let api = self.payment_api.clone();
-        let allocation_id = self.allocation_id.clone();
+        let allocation_ids: Vec<_> = self.allocations.drain(..).map(|(id, _)| id).collect();
+        if allocation_ids.is_empty() {
+            return Ok(());
+        }
         let _ = ctx.spawn(
             async move {
                 log::info!("Releasing allocation");
-                api.release_allocation(&allocation_id).await;
+                for allocation_id in allocation_ids {
+                    api.release_allocation(&allocation_id).await;
+                }
             }
             .into_actor(self),
         );
@@ -237,11 +315,29 @@ impl Handler<ReleaseAllocation> for PaymentManager {
     }
 }
 
+fn start_payments(
+    payment_api: &ya_client::payment::requestor::PaymentRequestorApi,
+) -> Addr<PaymentManager> {
+    let now = Utc::now();
+    let manager = PaymentManager {
+        payment_api: payment_api.clone(),
+        allocations: Vec::new(),
+        total_amount: 0.into(),
+        amount_paid: 0.into(),
+        valid_agreements: Default::default(),
+        rejected_agreements: Default::default(),
+        last_debit_note_event: now,
+        last_invoice_event: now,
+    };
+    manager.start()
+}
+
+/// Allocates funds for `n_tasks` more subtasks.
 async fn allocate_funds_for_task(
     payment_api: &ya_client::payment::requestor::PaymentRequestorApi,
+    payment_man: &Addr<PaymentManager>,
     n_tasks: usize,
-) -> anyhow::Result<Addr<PaymentManager>> {
-    let now = Utc::now();
+) -> anyhow::Result<()> {
     let total_amount: BigDecimal = ((n_tasks * 8) as u64).into();
     let new_allocation = model::payment::NewAllocation {
         //address: None,
@@ -252,17 +348,42 @@ async fn allocate_funds_for_task(
     };
     let allocation = payment_api.create_allocation(&new_allocation).await?;
     log::info!("Allocated {} GNT.", &allocation.total_amount);
+    payment_man
+        .send(AddAllocation {
+            allocation_id: allocation.allocation_id,
+            total_amount,
+        })
+        .await?;
+    Ok(())
+}
 
-    let manager = PaymentManager {
-        payment_api: payment_api.clone(),
-        allocation_id: allocation.allocation_id,
-        total_amount,
-        amount_paid: 0.into(),
-        valid_agreements: Default::default(),
-        last_debit_note_event: now,
-        last_invoice_event: now,
-    };
-    Ok(manager.start())
+/// Funds for the subtasks of a wave, allocated before each of them starts.
+///
+/// A streaming split does not tell how many subtasks there will be, so
+/// allocations grow in blocks, each as large as all allocated before.
+struct Funds {
+    payment_api: ya_client::payment::requestor::PaymentRequestorApi,
+    payment_man: Addr<PaymentManager>,
+    allocated: usize,
+    reserved: usize,
+}
+
+impl Funds {
+    /// Allocates funds for `n_tasks` subtasks known up front.
+    async fn allocate(&mut self, n_tasks: usize) -> anyhow::Result<()> {
+        allocate_funds_for_task(&self.payment_api, &self.payment_man, n_tasks).await?;
+        self.allocated += n_tasks;
+        Ok(())
+    }
+
+    /// Makes sure an allocation covers one more subtask.
+    async fn reserve(&mut self) -> anyhow::Result<()> {
+        if self.reserved == self.allocated {
+            self.allocate(self.allocated.max(1)).await?;
+        }
+        self.reserved += 1;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -271,14 +392,19 @@ struct TaskResult {
     task_def: TaskDef,
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_task(
     storage: DistStorage,
+    shared_files: Arc<futures::lock::Mutex<HashMap<String, String>>>,
     client: WebClient,
     p: Addr<PaymentManager>,
     a: Addr<AgreementProducer>,
     output_path: PathBuf,
-    merge_path: PathBuf,
+    task_output_path: PathBuf,
+    idx: usize,
     task: TaskDef,
+    protocol: u32,
+    limits: Limits,
 ) -> anyhow::Result<TaskResult> {
     let mut commands = Vec::new();
 
@@ -286,22 +412,47 @@ async fn process_task(
     commands.push(serde_json::json!({"start": { "args": [] }}));
 
     let input_path: PathBuf = "/in".into();
-    for blob_path in task.blobs() {
-        let file_name = storage.upload_file(&output_path.join(blob_path)).await?;
-        commands.push(serde_json::json!({"transfer": {
+    let blobs = task.blob_refs().map(|blob| (blob, false));
+    for (blob, is_dir) in blobs.chain(task.blob_dirs().map(|blob| (blob, true))) {
+        let file_name = if blob.shared {
+            // Held during the upload, so other subtasks wait for it instead of uploading again.
+            let mut shared_files = shared_files.lock().await;
+            match shared_files.get(&blob.path) {
+                Some(file_name) => file_name.clone(),
+                None => {
+                    let file_name = storage.upload_blob(&output_path.join(&blob.path)).await?;
+                    shared_files.insert(blob.path.clone(), file_name.clone());
+                    file_name
+                }
+            }
+        } else {
+            storage.upload_blob(&output_path.join(&blob.path)).await?
+        };
+        let mut transfer = serde_json::json!({
             "from": file_name,
-            "to": format!("container:/in/{}", blob_path)
-        }}));
+            "to": format!("container:/in/{}", blob.path)
+        });
+        if is_dir {
+            // Blob dirs travel as tar archives, unpacked by the provider.
+            transfer["format"] = "tar".into();
+        }
+        commands.push(serde_json::json!({ "transfer": transfer }));
     }
-    let task_file = storage.upload_json(&task).await?;
+    let task_file = storage
+        .upload_json(&TaskFile {
+            version: protocol,
+            data: &task,
+        })
+        .await?;
     commands.push(serde_json::json!({"transfer": {
         "from": task_file,
         "to": "container:/in/task.json"
     }}));
 
+    let run_idx = commands.len();
     commands.push(serde_json::json!({"run": {
       "entry_point": "main",
-      "args": ["exec", "/in/task.json", "/out/task.json"]
+      "args": ["exec", "/in/task.json", "/out/task.json", idx.to_string()]
     }}));
     let mut outputs = Vec::new();
     for blob_path in task.outputs() {
@@ -311,7 +462,17 @@ async fn process_task(
             "from": format!("container:/out/{}", blob_path),
             "to": slot.url()
         }}));
-        outputs.push((slot, merge_path.join(blob_path)))
+        outputs.push((slot, task_output_path.join(blob_path), false))
+    }
+    for dir_path in task.output_dirs() {
+        log::debug!("output dir={}", dir_path);
+        let slot = storage.download_slot().await?;
+        commands.push(serde_json::json!({"transfer": {
+            "from": format!("container:/out/{}", dir_path),
+            "to": slot.url(),
+            "format": "tar"
+        }}));
+        outputs.push((slot, task_output_path.join(dir_path), true))
     }
     let output_slot = storage.download_slot().await?;
     commands.push(serde_json::json!({"transfer": {
@@ -327,6 +488,7 @@ async fn process_task(
     loop {
         match try_process_task(
             commands_cnt,
+            run_idx,
             &script,
             &output_slot,
             &outputs,
@@ -334,14 +496,16 @@ async fn process_task(
             p.clone(),
             a.clone(),
             output_path.clone(),
-            merge_path.clone(),
+            task_output_path.clone(),
             task.clone(),
+            &limits,
         )
         .await
         {
             Ok(v) => return Ok(v),
             Err(e) => {
                 log::error!("fail to process subtask: {}", e);
+                limits.check_deadline()?;
                 log::info!("retry");
             }
         }
@@ -351,15 +515,17 @@ async fn process_task(
 #[allow(clippy::too_many_arguments)]
 async fn try_process_task(
     commands_cnt: usize,
+    run_idx: usize,
     script: &ya_client::model::activity::ExeScriptRequest,
     output_slot: &DistSlot,
-    outputs: &[(DistSlot, PathBuf)],
+    outputs: &[(DistSlot, PathBuf, bool)],
     client: WebClient,
     p: Addr<PaymentManager>,
     a: Addr<AgreementProducer>,
     output_path: PathBuf,
-    merge_path: PathBuf,
+    task_output_path: PathBuf,
     task: TaskDef,
+    limits: &Limits,
 ) -> anyhow::Result<TaskResult> {
     let activity_api = client.interface::<ya_client::activity::ActivityRequestorApi>()?;
     let agreement_id = a.send(NewAgreement).await??;
@@ -377,7 +543,12 @@ async fn try_process_task(
         .exec(script.clone(), &activity_id)
         .await?;
 
+    let mut results = Vec::new();
     loop {
+        if let Err(e) = limits.check_deadline() {
+            let _ = activity_api.control().destroy_activity(&activity_id).await;
+            return Err(e);
+        }
         let state = activity_api.state().get_state(&activity_id).await?;
         if !state.alive() {
             log::error!("activity {} is NOT ALIVE any more.", activity_id);
@@ -385,13 +556,13 @@ async fn try_process_task(
         }
 
         log::info!("activity {} state: {:?}", activity_id, state);
-        let results = match activity_api
+        match activity_api
             .control()
             .get_exec_batch_results(&activity_id, &batch_id, Some(60.), None)
             .await
         {
-            Ok(v) => v,
-            Err(ya_client::Error::TimeoutError { .. }) => Vec::default(),
+            Ok(v) => results = v,
+            Err(ya_client::Error::TimeoutError { .. }) => (),
             Err(e) => return Err(e.into()),
         };
 
@@ -404,26 +575,67 @@ async fn try_process_task(
         tokio::time::delay_for(Duration::from_millis(700)).await;
     }
 
-    // TODO: task output path resolve
-    let task_def = output_slot.download_json().await?;
-
+    // Kept before downloading outputs, so failed subtasks can be inspected.
+    let (stdout_path, stderr_path) = task_log_paths(task_output_path.parent().unwrap());
+    let mut stdout = String::new();
+    let mut stderr = String::new();
+    for result in &results {
+        if let Some(message) = &result.message {
+            if result.index as usize == run_idx {
+                stdout.push_str(message);
+            } else {
+                stderr.push_str(&format!("[command {}] {}\n", result.index, message));
+            }
+        }
+    }
+    fs::write(stdout_path, stdout)?;
+    fs::write(stderr_path, stderr)?;
+
+    let downloaded = async {
+        // TODO: task output path resolve
+        let task_def = output_slot.download_json::<TaskFile<TaskDef>>().await?.data;
+        for (slot, output, is_dir) in outputs {
+            log::info!(
+                "ExeScript finished. Downloading result...   [{}]",
+                activity_id
+            );
+            log::debug!("Downloading: {}", output.display());
+            if *is_dir {
+                slot.download_dir(output).await?;
+            } else {
+                slot.download(output).await?;
+            }
+        }
+        task_def.verify_blobs(&task_output_path)?;
+        Ok::<_, anyhow::Error>(task_def)
+    }
+    .await;
+    if let Err(e) = activity_api.control().destroy_activity(&activity_id).await {
+        log::error!("fail to destroy activity: {}", e);
+    }
+    // Only intact results are paid for, a failed download or a corrupted blob
+    // fails this attempt and the subtask is retried on another agreement.
+    let task_def = match downloaded {
+        Ok(task_def) => task_def,
+        Err(e) => {
+            let _ = p
+                .send(RejectAgreement {
+                    agreement_id: agreement_id.clone(),
+                })
+                .await;
+            return Err(e);
+        }
+    };
     let _err = p
         .send(AcceptAgreement {
             agreement_id: agreement_id.clone(),
         })
         .await;
-
-    for (slot, output) in outputs {
-        log::info!(
-            "ExeScript finished. Downloading result...   [{}]",
-            activity_id
-        );
-        log::debug!("Downloading: {}", output.display());
-        slot.download(output).await?;
-    }
-    if let Err(e) = activity_api.control().destroy_activity(&activity_id).await {
-        log::error!("fail to destroy activity: {}", e);
-    }
+    // Written last, so a resumed run only trusts subtasks with all outputs in place.
+    fs::write(
+        task_output_path.join("task.json"),
+        serde_json::to_vec_pretty(&task_def)?,
+    )?;
 
     log::info!("Task finished.   [{}]", activity_id);
     Ok(TaskResult {
@@ -432,13 +644,130 @@ async fn try_process_task(
     })
 }
 
+/// Runs a subtask for every work item from `items`, as they arrive, until the
+/// stream ends. `known_tasks` of them are already queued and get funds
+/// allocated at once.
+///
+/// Returns the outputs of the subtasks by split index.
+#[allow(clippy::too_many_arguments)]
+async fn run_subtasks(
+    engine: impl YagnaEngine,
+    image: Vec<u8>,
+    client: WebClient,
+    subnet: Option<String>,
+    timeout: Duration,
+    split_path: PathBuf,
+    protocol: u32,
+    limits: Limits,
+    known_tasks: usize,
+    items: mpsc::UnboundedReceiver<(usize, TaskDef, PathBuf)>,
+) -> anyhow::Result<Vec<(usize, TaskDef)>> {
+    let storage_server: Arc<str> = "http://3.249.139.167:8000/".into();
+    let payment_api: ya_client::payment::requestor::PaymentRequestorApi = client.interface()?;
+
+    // TODO: Catch error
+    let image = push_image(storage_server.clone(), image).await.unwrap();
+    log::info!("Binary image uploaded: {}", image);
+
+    let node_name = "test1";
+    let my_demand = engine.build_demand(
+        node_name,
+        &image,
+        timeout,
+        limits.resources(),
+        subnet.as_ref(),
+    )?;
+    let market_api: ya_client::market::MarketRequestorApi = client.interface()?;
+
+    let storage = DistStorage::new(storage_server);
+    // Shared blobs are uploaded once, by the first subtask using them.
+    let shared_files = Arc::new(futures::lock::Mutex::new(HashMap::new()));
+    let payment_man = start_payments(&payment_api);
+    let mut funds = Funds {
+        payment_api: payment_api.clone(),
+        payment_man: payment_man.clone(),
+        allocated: 0,
+        reserved: 0,
+    };
+    if known_tasks > 0 {
+        funds.allocate(known_tasks).await?;
+    }
+    let funds = Arc::new(futures::lock::Mutex::new(funds));
+
+    let results = async {
+        let a = agreement_producer(&market_api, &my_demand).await?;
+        // Providers do not relay progress files, only finished subtasks are shown.
+        let bar = subtasks_bar(0);
+        let results = items
+            // Sequential, so every subtask has funds before it is hired out.
+            .then(|item| {
+                let funds = funds.clone();
+                async move {
+                    funds.lock().await.reserve().await?;
+                    Ok::<_, anyhow::Error>(item)
+                }
+            })
+            .map_ok(|(idx, t, task_output_path)| {
+                bar.inc_length(1);
+                let bar = bar.clone();
+                process_task(
+                    storage.clone(),
+                    shared_files.clone(),
+                    client.clone(),
+                    payment_man.clone(),
+                    a.clone(),
+                    split_path.clone(),
+                    task_output_path,
+                    idx,
+                    t,
+                    protocol,
+                    limits.clone(),
+                )
+                .map_ok(move |result| {
+                    bar.inc(1);
+                    (idx, result.task_def)
+                })
+            })
+            .try_buffer_unordered(usize::MAX)
+            .try_collect::<Vec<_>>()
+            .await;
+        bar.finish_and_clear();
+        let _ = a.send(Kill).await;
+        results
+    }
+    .await;
+    let results = match results {
+        Ok(results) => results,
+        Err(e) => {
+            let _ = payment_man.send(ReleaseAllocation).await;
+            return Err(e);
+        }
+    };
+
+    loop {
+        let pending = payment_man.send(GetPending).await?;
+        if pending == 0 {
+            break;
+        }
+        log::warn!("still {} pending payments", pending);
+        tokio::time::delay_for(Duration::from_millis(700)).await;
+    }
+    payment_man.send(ReleaseAllocation).await?;
+    log::info!("Work done and paid. Enjoy results.");
+
+    Ok(results)
+}
+
 pub fn run(
     hub_addr: Option<String>,
     token: Option<String>,
     subnet: Option<String>,
-    engine: impl YagnaEngine + 'static,
+    engine: impl YagnaEngine + Send + Sync + 'static,
     wasm_path: &Path,
     timeout: Duration,
+    limits: &Limits,
+    resume: Option<&str>,
+    output_dir: Option<&Path>,
     args: &[String],
 ) -> anyhow::Result<()> {
     let _ = dotenv::dotenv().ok();
@@ -449,100 +778,174 @@ pub fn run(
     let client = ya_client::web::WebClient::with_token(&token);
 
     let mut sys = System::new("wasm-runner");
-    let mut w = WorkDir::new("lwg")?;
+    let mut w = WorkDir::for_run("lwg", resume)?;
+    w.set_app_protocol(check_app(engine.clone(), limits, wasm_path)?);
     let image = engine.build_image(wasm_path)?;
-    log::info!("Locally splitting work into tasks");
-    let output_path = w.split_output()?;
-    {
-        let mut split_args = Vec::new();
-        split_args.push("split".to_owned());
-        split_args.push("/task_dir/".to_owned());
-        split_args.extend(args.iter().cloned());
-        run_local_code(engine.clone(), wasm_path, &output_path, split_args)?;
-    }
-
-    let tasks_path = output_path.join("tasks.json");
-
-    log::debug!("reading: {}", tasks_path.display());
-    let tasks: Vec<TaskDef> =
-        serde_json::from_reader(fs::OpenOptions::new().read(true).open(tasks_path)?)?;
-    log::info!("Created {} tasks", tasks.len());
+    loop {
+        let split_path = w.split_output()?;
+        let finished_split = w.finished_split();
+        match &finished_split {
+            Some(_) if w.round() == 0 => log::info!("Reusing split from previous attempt"),
+            Some(_) => (),
+            None => log::info!("Locally splitting work into tasks"),
+        }
+        // Without a finished split, the first wave runs work items as the split streams them.
+        let mut streaming = finished_split.is_none();
+        let mut tasks = finished_split.unwrap_or_default();
+
+        let merge_path = w.merge_path()?;
+        let output_file = merge_path.join("tasks.json");
+        let merge_engine = engine.clone();
+
+        let mut task_output_paths = Vec::new();
+        let mut outputs = Vec::new();
+        let mut spawned = Vec::new();
+        // Work items spawned by a wave of tasks run in the next wave.
+        let mut wave_start = 0;
+        while streaming || wave_start < tasks.len() {
+            limits.check_deadline()?;
+            let (sender, items) = mpsc::unbounded();
+            let mut reused = Vec::new();
+            let mut pending = 0;
+            if !streaming {
+                for (idx, task) in (wave_start..).zip(tasks[wave_start..].iter().cloned()) {
+                    let task_output_path = w.task_path(idx)?.join("out");
+                    fs::create_dir_all(&task_output_path)?;
+                    match w.finished_task(idx) {
+                        Some(task_def) => reused.push((idx, task_def)),
+                        None => {
+                            let _ = sender.unbounded_send((idx, task, task_output_path));
+                            pending += 1;
+                        }
+                    }
+                }
+                if !reused.is_empty() {
+                    log::info!(
+                        "Skipping {} tasks finished by previous attempt",
+                        reused.len()
+                    );
+                }
+            }
 
-    let merge_path = w.merge_path()?;
-    let output_file = merge_path.join("tasks.json");
-    let merge_path_ref = merge_path.clone();
+            let run = run_subtasks(
+                engine.clone(),
+                image.clone(),
+                client.clone(),
+                subnet.clone(),
+                timeout,
+                split_path.clone(),
+                w.app_protocol(),
+                limits.clone(),
+                pending,
+                items,
+            );
+            let cancelled = AtomicBool::new(false);
+            let (split, results) = thread::scope(|s| {
+                let split = streaming.then(|| {
+                    let (split_engine, w, split_path, cancelled) =
+                        (engine.clone(), &mut w, &split_path, &cancelled);
+                    s.spawn(move || {
+                        run_split_streaming(
+                            split_engine,
+                            limits,
+                            wasm_path,
+                            args,
+                            split_path,
+                            cancelled,
+                            &mut |idx, task| {
+                                let task_output_path = w.task_path(idx)?.join("out");
+                                fs::create_dir_all(&task_output_path)?;
+                                let _ =
+                                    sender.unbounded_send((idx, task.clone(), task_output_path));
+                                Ok(())
+                            },
+                        )
+                    })
+                });
+                let results = if streaming || pending > 0 {
+                    sys.block_on(run)
+                } else {
+                    Ok(Vec::new())
+                };
+                if results.is_err() {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+                (split.map(|split| split.join().unwrap()), results)
+            });
+            if let Some(split) = split {
+                tasks = split?;
+                log::info!("Created {} tasks", tasks.len());
+                streaming = false;
+            }
+            let results = results?;
 
-    let storage_server: Arc<str> = "http://3.249.139.167:8000/".into();
-    let payment_api: ya_client::payment::requestor::PaymentRequestorApi = client.interface()?;
-    let task_output_path = output_path;
-    let merge_engine = engine.clone();
-    sys.block_on(async move {
-        // TODO: Catch error
-        let image = push_image(storage_server.clone(), image).await.unwrap();
-        log::info!("Binary image uploaded: {}", image);
-
-        let node_name = "test1";
-        let my_demand = engine.build_demand(node_name, &image, timeout, subnet.as_ref())?;
-        let market_api: ya_client::market::MarketRequestorApi = client.interface()?;
-
-        let storage = DistStorage::new(storage_server);
-        let output_tasks = merge_path_ref.join("tasks.json");
-        let payment_man = allocate_funds_for_task(&payment_api, tasks.len()).await?;
-
-        let agreements = {
-            let a = agreement_producer(&market_api, &my_demand).await?;
-            let agreements = futures::future::join_all(tasks.into_iter().map(|t| {
-                process_task(
-                    storage.clone(),
-                    client.clone(),
-                    payment_man.clone(),
-                    a.clone(),
-                    task_output_path.clone(),
-                    merge_path_ref.clone(),
-                    t,
-                )
-            }))
-            .await;
-            let _ = a.send(Kill).await;
-            agreements
-        };
+            let wave_end = tasks.len();
+            outputs.resize(wave_end, None);
+            for (idx, task_def) in reused.into_iter().chain(results) {
+                outputs[idx] = Some(task_def);
+            }
+            // Spawned tasks are numbered in split order, regardless of completion order.
+            for idx in wave_start..wave_end {
+                let task_output_path = w.task_path(idx)?.join("out");
+                let output = outputs[idx]
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("missing task output"))?;
+                let children = w.spawned_tasks(&task_output_path, output, tasks.len())?;
+                spawned.push((tasks.len()..tasks.len() + children.len()).collect::<Vec<_>>());
+                tasks.extend(children);
+                task_output_paths.push(task_output_path);
+            }
+            if tasks.len() > wave_end {
+                log::info!("Tasks spawned {} more", tasks.len() - wave_end);
+            }
+            wave_start = wave_end;
+        }
 
+        let mut inputs = Vec::new();
+        for task in tasks {
+            inputs.push(task.rebase_to(&split_path, &merge_path)?);
+        }
+        std::fs::write(
+            merge_path.join("tasks_input.json"),
+            serde_json::to_vec_pretty(&w.task_file(&inputs))?,
+        )?;
         let mut tasks = Vec::new();
-        for res in agreements {
-            let result = res?;
-            tasks.push(result.task_def);
+        for ((task_def, task_output_path), children) in
+            outputs.into_iter().zip(task_output_paths).zip(spawned)
+        {
+            let task_def = task_def.ok_or_else(|| anyhow::anyhow!("missing task output"))?;
+            tasks.push(
+                task_def
+                    .link_spawned(children)
+                    .rebase_to(&task_output_path, &merge_path)?,
+            );
         }
+        std::fs::write(
+            output_file,
+            serde_json::to_vec_pretty(&w.task_file(&tasks))?,
+        )?;
 
-        std::fs::write(output_tasks, serde_json::to_vec_pretty(&tasks)?)?;
-        loop {
-            let pending = payment_man.send(GetPending).await?;
-            if pending == 0 {
-                break;
-            }
-            log::warn!("still {} pending payments", pending);
-            tokio::time::delay_for(Duration::from_millis(700)).await;
+        {
+            let mut merge_args = vec![
+                "merge".to_owned(),
+                "/task_dir/merge/tasks_input.json".to_owned(),
+                "/task_dir/merge/tasks.json".to_owned(),
+                "--".to_owned(),
+            ];
+            merge_args.extend(args.iter().cloned());
+
+            run_merge_code(
+                merge_engine,
+                limits,
+                wasm_path,
+                &merge_path,
+                merge_args,
+                output_dir,
+            )?;
+        }
+        if !w.next_round()? {
+            break;
         }
-        payment_man.send(ReleaseAllocation).await?;
-        log::info!("Work done and paid. Enjoy results.");
-
-        Ok::<_, anyhow::Error>(())
-    })?;
-
-    {
-        let mut merge_args = vec![
-            "merge".to_owned(),
-            "/task_dir/split/tasks.json".to_owned(),
-            "/task_dir/merge/tasks.json".to_owned(),
-            "--".to_owned(),
-        ];
-        merge_args.extend(args.iter().cloned());
-
-        run_local_code(
-            merge_engine,
-            wasm_path,
-            merge_path.parent().unwrap(),
-            merge_args,
-        )?;
     }
 
     Ok(())
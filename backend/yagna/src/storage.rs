@@ -1,5 +1,6 @@
 use actix_http::HttpMessage;
 use futures::prelude::*;
+use gwr_backend::archive;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fs::OpenOptions;
@@ -34,7 +35,8 @@ impl DistSlot {
         let payload = response.take_payload();
         let mut fs = OpenOptions::new()
             .write(true)
-            .create_new(true)
+            .create(true)
+            .truncate(true)
             .open(out_path)?;
         Ok(payload
             .for_each(|b| {
@@ -45,6 +47,13 @@ impl DistSlot {
             .await)
     }
 
+    /// Downloads a blob dir uploaded as a tar archive and unpacks it at `out_path`.
+    pub async fn download_dir(&self, out_path: &Path) -> anyhow::Result<()> {
+        let tar_path = archive::archive_path(out_path);
+        self.download(&tar_path).await?;
+        archive::unpack_dir(&tar_path, out_path)
+    }
+
     pub async fn download_json<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
         let c = awc::Client::new();
         let b = c
@@ -86,6 +95,17 @@ impl DistStorage {
         self.upload_bytes("blob", std::fs::read(path)?).await
     }
 
+    /// Uploads a blob file, or a blob dir packed into a tar archive.
+    pub async fn upload_blob(&self, path: &Path) -> anyhow::Result<String> {
+        if !path.is_dir() {
+            return self.upload_file(path).await;
+        }
+        let tar_path = archive::pack_dir(path)?;
+        let data = std::fs::read(&tar_path)?;
+        std::fs::remove_file(&tar_path)?;
+        self.upload_bytes("blob", data).await
+    }
+
     pub async fn upload_json<T: Serialize>(&self, obj: &T) -> anyhow::Result<String> {
         let bytes = serde_json::to_vec_pretty(obj)?;
         self.upload_bytes("json", bytes).await
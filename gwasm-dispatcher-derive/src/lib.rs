@@ -0,0 +1,107 @@
+//! Derive macros for `gwasm-dispatcher`.
+//!
+//! `#[derive(TaskDef)]` turns a struct with named fields into a work item:
+//! every field becomes one task argument, in declaration order. `Blob`,
+//! `Output` and their `Dir` counterparts are passed as files, everything else
+//! as JSON metadata.
+//! The struct can then be returned by a splitter and taken as the only
+//! argument of an executor.
+//!
+//! `#[derive(TaskOutput)]` does the same for values returned by executors.
+//!
+//! Use them through `gwasm_dispatcher` with the `derive` feature enabled.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+#[proc_macro_derive(TaskDef)]
+pub fn derive_task_def(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, true)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+#[proc_macro_derive(TaskOutput)]
+pub fn derive_task_output(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input, false)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand(input: &DeriveInput, task_input: bool) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "task definitions require a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "task definitions can only be derived for structs",
+            ))
+        }
+    };
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let names = fields.iter().map(|f| &f.ident).collect::<Vec<_>>();
+    let tys = fields.iter().map(|f| &f.ty).collect::<Vec<_>>();
+
+    let marker = if task_input {
+        quote! {
+            impl #impl_generics ::gwasm_dispatcher::TaskInput for #name #ty_generics #where_clause {}
+        }
+    } else {
+        quote! {}
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::gwasm_dispatcher::IntoTaskDef for #name #ty_generics #where_clause {
+            fn into_task_def(
+                self,
+                base: &::std::path::Path,
+            ) -> ::std::result::Result<::gwasm_dispatcher::TaskDef, ::gwasm_dispatcher::Error> {
+                Ok(::gwasm_dispatcher::TaskDef(vec![
+                    #(::gwasm_dispatcher::IntoTaskArg::into_arg(self.#names, base)?),*
+                ]))
+            }
+        }
+
+        impl #impl_generics ::gwasm_dispatcher::FromTaskDef for #name #ty_generics #where_clause {
+            fn from_task_def(
+                task: ::gwasm_dispatcher::TaskDef,
+                base: &::std::path::Path,
+            ) -> ::std::result::Result<Self, ::gwasm_dispatcher::Error> {
+                task.check_args(&[
+                    #(<#tys as ::gwasm_dispatcher::FromTaskArg>::arg_kind()),*
+                ])?;
+                // Arity checked above, spawn records only follow the args.
+                let mut task_iter = task.0.into_iter();
+                Ok(#name {
+                    #(#names: <#tys as ::gwasm_dispatcher::FromTaskArg>::from_arg(
+                        task_iter.next().unwrap(),
+                        base,
+                    )?),*
+                })
+            }
+
+            fn arg_kinds() -> ::std::option::Option<::std::vec::Vec<::gwasm_dispatcher::ArgKind>> {
+                ::std::option::Option::Some(vec![
+                    #(<#tys as ::gwasm_dispatcher::FromTaskArg>::arg_kind()),*
+                ])
+            }
+        }
+
+        #marker
+    })
+}
@@ -0,0 +1,34 @@
+use gwasm_dispatcher::*;
+
+#[derive(TaskDef)]
+struct Chunk {
+    numbers: Vec<u64>,
+}
+
+#[derive(TaskOutput)]
+struct ChunkSum {
+    sum: u64,
+}
+
+fn main() {
+    dispatcher::run(
+        move |_: &mut dyn SplitContext| {
+            const NUM_SUBTASKS: usize = 10;
+            let arr: Vec<u64> = (1..=100).collect();
+            arr.chunks(NUM_SUBTASKS)
+                .map(|x| Chunk {
+                    numbers: x.to_vec(),
+                })
+                .collect::<Vec<_>>()
+        },
+        |chunk: Chunk| ChunkSum {
+            sum: chunk.numbers.into_iter().sum(),
+        },
+        |_: &Vec<String>, results: Vec<(Chunk, ChunkSum)>| {
+            let given: u64 = results.iter().map(|(_, result)| result.sum).sum();
+            let expected: u64 = (1..=100).sum();
+            assert_eq!(expected, given, "sums should be equal")
+        },
+    )
+    .unwrap()
+}
@@ -1,19 +1,50 @@
 use crate::error::Error;
-use crate::taskdef::{FromTaskArg, IntoTaskArg, TaskArg};
+use crate::taskdef::{
+    digest_path, ArgKind, BlobDigest, BlobRef, FromTaskArg, IntoTaskArg, TaskArg, SHARED_DIR,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::fs;
 /**
  Binary Large Objects
 
 **/
-use std::io::{self, Read, Seek, Write};
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
-pub struct Blob(PathBuf);
+/// Digest of a blob, computed the first time the blob or any of its clones
+/// is passed to a subtask.
+#[derive(Clone, Default)]
+struct DigestCache(Arc<OnceLock<BlobDigest>>);
+
+impl DigestCache {
+    fn get(&self, path: &Path) -> io::Result<BlobDigest> {
+        if let Some(digest) = self.0.get() {
+            return Ok(digest.clone());
+        }
+        let digest = digest_path(path)?;
+        Ok(self.0.get_or_init(|| digest).clone())
+    }
+}
+
+/// Reference to a blob file; clones refer to the same file, as work items
+/// sharing a blob do, and hash it only once.
+#[derive(Clone)]
+pub struct Blob(PathBuf, DigestCache);
 pub struct Output(pub(crate) PathBuf);
 
+/// Directory of blobs, passed to a subtask with every file below it.
+#[derive(Clone)]
+pub struct BlobDir(PathBuf, DigestCache);
+
+/// Directory for output files, passed back as a `BlobDir` once written.
+pub struct OutputDir(pub(crate) PathBuf);
+
 impl Blob {
     pub fn from_output(output: Output) -> Self {
-        Blob(output.0)
+        Blob(output.0, DigestCache::default())
     }
 
     pub fn open(&self) -> io::Result<impl Read + Seek> {
@@ -52,13 +83,101 @@ impl Output {
     }
 }
 
+impl BlobDir {
+    pub fn from_output_dir(output: OutputDir) -> Self {
+        BlobDir(output.0, DigestCache::default())
+    }
+
+    /// Opens the file at `name`, relative to the directory.
+    pub fn open(&self, name: impl AsRef<Path>) -> io::Result<impl Read + Seek> {
+        let path = em_canonicalize(&self.0)?.join(name);
+        fs::OpenOptions::new().read(true).open(&path)
+    }
+
+    /// Names of the files below the directory, relative to it.
+    pub fn files(&self) -> io::Result<Vec<String>> {
+        fn walk(dir: &Path, prefix: &str, files: &mut Vec<String>) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+                if entry.file_type()?.is_dir() {
+                    walk(&entry.path(), &format!("{}/", name), files)?;
+                } else {
+                    files.push(name);
+                }
+            }
+            Ok(())
+        }
+
+        let mut files = Vec::new();
+        walk(&em_canonicalize(&self.0)?, "", &mut files)?;
+        files.sort();
+        Ok(files)
+    }
+}
+
+impl OutputDir {
+    /// Allocates output file at `name`, relative to the directory.
+    pub fn file(&self, name: impl AsRef<Path>) -> io::Result<Output> {
+        let path = em_canonicalize(&self.0)?.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Output(path))
+    }
+
+    #[inline]
+    pub fn into_blob_dir(self) -> BlobDir {
+        BlobDir::from_output_dir(self)
+    }
+}
+
+/// Blob holding a value of `T` in a compact binary encoding.
+///
+/// The value is written when the blob is created and read back when the task
+/// arg is decoded, so large structured inputs stay out of `tasks.json`.
+pub struct TypedBlob<T> {
+    blob: Blob,
+    value: T,
+}
+
+impl<T: Serialize + DeserializeOwned> TypedBlob<T> {
+    /// Writes `value` into `output`.
+    pub fn new(output: Output, value: T) -> Result<Self, Error> {
+        let mut w = BufWriter::new(output.open()?);
+        bincode::serialize_into(&mut w, &value)?;
+        w.flush()?;
+        Ok(TypedBlob {
+            blob: Blob::from_output(output),
+            value,
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T> Deref for TypedBlob<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
 impl IntoTaskArg for Blob {
     fn into_arg(self, base: &Path) -> Result<TaskArg, Error> {
         let cpath = em_canonicalize(&self.0)?;
         let path = cpath.strip_prefix(base)?;
-        path.to_str()
-            .ok_or_else(|| Error::invalid_path(&self.0))
-            .map(|v| TaskArg::Blob(v.replace('\\', "/")))
+        let mut blob = BlobRef::with_digest(
+            path.to_str()
+                .ok_or_else(|| Error::invalid_path(&self.0))?
+                .replace('\\', "/"),
+            self.1.get(&cpath)?,
+        );
+        blob.shared = path.starts_with(SHARED_DIR);
+        Ok(TaskArg::Blob(blob))
     }
 }
 
@@ -84,32 +203,170 @@ fn em_canonicalize(path: &Path) -> io::Result<PathBuf> {
 
 #[cfg(not(target_arch = "wasm32"))]
 fn em_canonicalize(path: &Path) -> io::Result<PathBuf> {
-    path.canonicalize()
+    // Outputs are canonicalized before they are created.
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !path.exists() => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            Ok(parent.canonicalize()?.join(name))
+        }
+        _ => path.canonicalize(),
+    }
 }
 
 impl FromTaskArg for Blob {
     fn from_arg(arg: TaskArg, base: &Path) -> Result<Self, Error> {
         match arg {
-            TaskArg::Blob(path) => Ok(Blob(base.join(path))),
+            TaskArg::Blob(blob) => Ok(Blob(base.join(blob.path), DigestCache::default())),
             _ => Err(Error::BlobExpected),
         }
     }
+
+    fn arg_kind() -> ArgKind {
+        ArgKind::Blob
+    }
 }
 
 impl IntoTaskArg for Output {
     fn into_arg(self, base: &Path) -> Result<TaskArg, Error> {
         let path = self.0.strip_prefix(base)?;
-        path.to_str()
-            .ok_or_else(|| Error::invalid_path(&self.0))
-            .map(|v| TaskArg::Output(v.into()))
+        let path = path.to_str().ok_or_else(|| Error::invalid_path(&self.0))?;
+        // Outputs returned by an executor are written by now.
+        Ok(TaskArg::Output(if self.0.is_file() {
+            BlobRef::for_file(path, &self.0)?
+        } else {
+            BlobRef::new(path)
+        }))
     }
 }
 
 impl FromTaskArg for Output {
     fn from_arg(arg: TaskArg, base: &Path) -> Result<Self, Error> {
         Ok(match arg {
-            TaskArg::Output(path) => Output(PathBuf::from(&base.join(path))),
+            TaskArg::Output(output) => Output(PathBuf::from(&base.join(output.path))),
             _ => return Err(Error::OutputExpected),
         })
     }
+
+    fn arg_kind() -> ArgKind {
+        ArgKind::Output
+    }
+}
+
+impl<T> IntoTaskArg for TypedBlob<T> {
+    fn into_arg(self, base: &Path) -> Result<TaskArg, Error> {
+        self.blob.into_arg(base)
+    }
+}
+
+impl<T: DeserializeOwned> FromTaskArg for TypedBlob<T> {
+    fn from_arg(arg: TaskArg, base: &Path) -> Result<Self, Error> {
+        let blob = Blob::from_arg(arg, base)?;
+        let value = bincode::deserialize_from(BufReader::new(blob.open()?))?;
+        Ok(TypedBlob { blob, value })
+    }
+
+    fn arg_kind() -> ArgKind {
+        ArgKind::Blob
+    }
+}
+
+impl IntoTaskArg for BlobDir {
+    fn into_arg(self, base: &Path) -> Result<TaskArg, Error> {
+        let cpath = em_canonicalize(&self.0)?;
+        let path = cpath.strip_prefix(base)?;
+        let mut blob = BlobRef::with_digest(
+            path.to_str()
+                .ok_or_else(|| Error::invalid_path(&self.0))?
+                .replace('\\', "/"),
+            self.1.get(&cpath)?,
+        );
+        blob.shared = path.starts_with(SHARED_DIR);
+        Ok(TaskArg::BlobDir(blob))
+    }
+}
+
+impl FromTaskArg for BlobDir {
+    fn from_arg(arg: TaskArg, base: &Path) -> Result<Self, Error> {
+        match arg {
+            TaskArg::BlobDir(blob) => Ok(BlobDir(base.join(blob.path), DigestCache::default())),
+            _ => Err(Error::BlobDirExpected),
+        }
+    }
+
+    fn arg_kind() -> ArgKind {
+        ArgKind::BlobDir
+    }
+}
+
+impl IntoTaskArg for OutputDir {
+    fn into_arg(self, base: &Path) -> Result<TaskArg, Error> {
+        let path = self.0.strip_prefix(base)?;
+        let path = path.to_str().ok_or_else(|| Error::invalid_path(&self.0))?;
+        Ok(TaskArg::OutputDir(if self.0.is_dir() {
+            BlobRef::for_file(path, &self.0)?
+        } else {
+            BlobRef::new(path)
+        }))
+    }
+}
+
+impl FromTaskArg for OutputDir {
+    fn from_arg(arg: TaskArg, base: &Path) -> Result<Self, Error> {
+        match arg {
+            TaskArg::OutputDir(output) => {
+                let output = OutputDir(base.join(output.path));
+                // Created up front, so backends find it even if nothing is written to it.
+                fs::create_dir_all(em_canonicalize(&output.0)?)?;
+                Ok(output)
+            }
+            _ => Err(Error::OutputDirExpected),
+        }
+    }
+
+    fn arg_kind() -> ArgKind {
+        ArgKind::OutputDir
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_typed_blob() {
+        let base = std::env::temp_dir().join("gwasm-dispatcher-test_typed_blob");
+        fs::create_dir_all(&base).unwrap();
+        let base = base.canonicalize().unwrap();
+
+        let matrix = vec![vec![1.5f64, -2.0], vec![0.0, 4.25]];
+        let blob = TypedBlob::new(Output(base.join("matrix.bin")), matrix.clone()).unwrap();
+        assert_eq!(*blob, matrix);
+
+        let arg = blob.into_arg(&base).unwrap();
+        let blob = TypedBlob::<Vec<Vec<f64>>>::from_arg(arg.clone(), &base).unwrap();
+        assert_eq!(blob.into_inner(), matrix);
+        assert!(TypedBlob::<[u64; 8]>::from_arg(arg, &base).is_err());
+    }
+
+    #[test]
+    fn test_shared_digest() {
+        let base = std::env::temp_dir().join("gwasm-dispatcher-test_shared_digest");
+        fs::create_dir_all(&base).unwrap();
+        let base = base.canonicalize().unwrap();
+
+        let blob = Output(base.join("shared.bin")).bytes(b"first").unwrap();
+        let copy = blob.clone();
+        let first = blob.into_arg(&base).unwrap();
+        // Clones reuse the digest instead of reading the file again.
+        fs::write(base.join("shared.bin"), b"second").unwrap();
+        assert_eq!(copy.into_arg(&base).unwrap(), first);
+
+        let fresh = Blob::from_arg(first.clone(), &base).unwrap();
+        assert_ne!(fresh.into_arg(&base).unwrap(), first);
+        fs::remove_dir_all(&base).unwrap();
+    }
 }
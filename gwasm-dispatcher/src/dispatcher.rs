@@ -7,23 +7,49 @@ use std::iter::FromIterator;
 
 use crate::error::DynError;
 use crate::executor::{exec_for, Executor};
-use crate::merger::{merge_for, Merger};
-use crate::splitter::{split_into, Splitter};
-use crate::taskdef::{FromTaskDef, IntoTaskDef, TaskDef};
+use crate::merger::{merge_for, MergeOutcome, Merger};
+use crate::splitter::{split_each_into, Splitter};
+use crate::taskdef::{ArgKind, FromTaskDef, FromTaskOutput, IntoTaskDef, TaskDef, TaskFile};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::error::Error;
-use std::io::{BufReader, BufWriter};
+use std::io::{self, BufReader, BufWriter, Write};
 
 pub type TaskResult<In, Out> = Vec<(In, Out)>;
 
+/// Version of the command protocol between the runner and apps, reported by
+/// `info` and stored in task files.
+///
+/// Version 2 starts task files with a header, see `TaskFile`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Name and version of an app, reported by `info`.
+///
+/// Usually `AppInfo { name: env!("CARGO_PKG_NAME"), version: env!("CARGO_PKG_VERSION") }`.
+#[derive(Clone, Debug, Serialize)]
+pub struct AppInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
 #[derive(Debug)]
 pub enum ApiError {
     NoParent,
     NoSeparator,
-    NoCommand { command: String },
-    InvalidParamsFormat { message: String },
-    JsonError { error: serde_json::error::Error },
+    MissingCommand,
+    MissingArgs {
+        command: String,
+        usage: &'static str,
+    },
+    NoCommand {
+        command: String,
+    },
+    InvalidParamsFormat {
+        message: String,
+    },
+    JsonError {
+        error: serde_json::error::Error,
+    },
 }
 
 impl fmt::Display for ApiError {
@@ -31,6 +57,12 @@ impl fmt::Display for ApiError {
         match self {
             ApiError::NoParent => write!(f, "Can't find parent"),
             ApiError::NoSeparator => write!(f, "Expected -- separator."),
+            ApiError::MissingCommand => {
+                write!(f, "Expected command: split, exec, merge or info.")
+            }
+            ApiError::MissingArgs { command, usage } => {
+                write!(f, "Missing arguments, usage: {} {}.", command, usage)
+            }
             ApiError::NoCommand { command } => write!(f, "No such command {}.", command),
             ApiError::InvalidParamsFormat { message } => {
                 write!(f, "Invalid params format: {}.", message)
@@ -68,39 +100,112 @@ fn save_to<T: Serialize>(output_file: &Path, value: &T) -> Result<(), DynError>
     Ok(())
 }
 
+/// Writes the list of a task file item by item, so it is never held in memory.
+struct TaskListWriter<W: Write> {
+    out: W,
+    first: bool,
+}
+
+impl<W: Write> TaskListWriter<W> {
+    fn new(mut out: W) -> io::Result<Self> {
+        write!(out, "{{\"version\":{},\"data\":[", PROTOCOL_VERSION)?;
+        Ok(TaskListWriter { out, first: true })
+    }
+
+    fn push<T: Serialize>(&mut self, item: &T) -> io::Result<()> {
+        if !self.first {
+            self.out.write_all(b",")?;
+        }
+        self.first = false;
+        serde_json::to_writer(&mut self.out, item).map_err(io::Error::from)
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.out.write_all(b"]}")?;
+        self.out.flush()
+    }
+}
+
+fn expect_args(
+    command: &str,
+    args: &[String],
+    count: usize,
+    usage: &'static str,
+) -> Result<(), ApiError> {
+    if args.len() < count {
+        return Err(ApiError::MissingArgs {
+            command: command.to_owned(),
+            usage,
+        });
+    }
+    Ok(())
+}
+
 fn split_step<S: Splitter<WorkItem = In>, In: IntoTaskDef + FromTaskDef>(
     splitter: S,
     args: &[String],
 ) -> Result<(), DynError> {
-    // TODO: check param len
+    expect_args("split", args, 1, "<work-dir> [args...]")?;
     let work_dir = PathBuf::from(&args[0]);
     let split_args = &Vec::from_iter(args[1..].iter().cloned());
 
-    let split_params = split_into(splitter, &work_dir, split_args)?;
-
-    let split_out_path = work_dir.join("tasks.json");
-    save_to(&split_out_path, &split_params)
+    // Work items are also streamed, one JSON line each, so runners can start
+    // subtasks before the split is done.
+    let mut stream = BufWriter::new(fs::File::create(work_dir.join("tasks.ndjson"))?);
+    let mut split_params = TaskListWriter::new(BufWriter::new(fs::File::create(
+        work_dir.join("tasks.json"),
+    )?))?;
+    split_each_into(splitter, &work_dir, split_args, &mut |task| {
+        serde_json::to_writer(&mut stream, &task)?;
+        stream.write_all(b"\n")?;
+        stream.flush()?;
+        split_params.push(&task)?;
+        Ok(())
+    })?;
+    split_params.finish()?;
+    Ok(())
 }
 
-fn execute_step<E: Executor<In, Out>, In: FromTaskDef, Out: IntoTaskDef>(
+fn execute_step<E: Executor<In, Out>, In: FromTaskDef + IntoTaskDef, Out: IntoTaskDef>(
     executor: E,
     args: &[String],
 ) -> Result<(), DynError> {
+    expect_args("exec", args, 2, "<task.json> <output.json> [index]")?;
     let params_path = PathBuf::from(args[0].clone());
     let input_dir = params_path.parent().ok_or(ApiError::NoParent)?;
     let output_desc_path = PathBuf::from(args[1].clone());
     let output_dir = output_desc_path.parent().ok_or(ApiError::NoParent)?;
-
-    let input_params = load_from(&params_path)?;
-    let output_desc = exec_for(&executor, input_params, input_dir, output_dir)?;
-
-    save_to(&output_desc_path, &output_desc)
+    // Runners before subtask indexes do not pass one.
+    let index = match args.get(2) {
+        Some(index) => index.parse().map_err(|_| ApiError::InvalidParamsFormat {
+            message: format!("invalid subtask index {}", index),
+        })?,
+        None => 0,
+    };
+
+    let input: TaskFile<TaskDef> = load_from(&params_path)?;
+    let output_desc = exec_for(&executor, index, input.data, input_dir, output_dir)?;
+
+    // Answered in the format of the runner.
+    save_to(
+        &output_desc_path,
+        &TaskFile {
+            version: input.version,
+            data: output_desc,
+        },
+    )
 }
 
-fn merge_step<M: Merger<In, Out>, In: FromTaskDef, Out: FromTaskDef>(
+fn merge_step<
+    M: Merger<In, Out, R>,
+    In: FromTaskDef + IntoTaskDef,
+    Out: FromTaskDef,
+    R: FromTaskOutput<Out>,
+>(
     merger: M,
     args: &[String],
 ) -> Result<(), DynError> {
+    expect_args("merge", args, 3, "<tasks.json> <outputs.json> -- [args...]")?;
     let tasks_params_path = PathBuf::from(args[0].clone());
     let tasks_outputs_path = PathBuf::from(args[1].clone());
 
@@ -111,39 +216,108 @@ fn merge_step<M: Merger<In, Out>, In: FromTaskDef, Out: FromTaskDef>(
         return Err(ApiError::NoSeparator.into());
     }
 
-    let input_params: Vec<TaskDef> = load_from(&tasks_params_path)?;
-    let outputs: Vec<TaskDef> = load_from(&tasks_outputs_path)?;
+    let input_params: TaskFile<Vec<TaskDef>> = load_from(&tasks_params_path)?;
+    let outputs: TaskFile<Vec<TaskDef>> = load_from(&tasks_outputs_path)?;
+    let version = input_params.version;
 
-    let in_out_pack = input_params.into_iter().zip(outputs.into_iter()).collect();
+    let in_out_pack = input_params.data.into_iter().zip(outputs.data).collect();
 
     let original_args = Vec::from_iter(args[3..].iter().cloned());
 
-    merge_for(
+    match merge_for(
         merger,
         &original_args,
         in_out_pack,
         split_work_dir,
         exec_work_dir,
-    )
+    )? {
+        MergeOutcome::Done => Ok(()),
+        // Picked up by the runner as the split of the next round.
+        MergeOutcome::Continue(tasks) => {
+            let next_dir = exec_work_dir.join("next");
+            fs::create_dir_all(&next_dir)?;
+            save_to(
+                &next_dir.join("tasks.json"),
+                &TaskFile {
+                    version,
+                    data: tasks,
+                },
+            )
+        }
+    }
 }
 
+/// Prints the protocol version and the shape of work items and results as JSON.
+fn info_step<In: FromTaskDef, Out: FromTaskDef>(app: Option<&AppInfo>) -> Result<(), DynError> {
+    fn args_json(kinds: Option<Vec<ArgKind>>) -> serde_json::Value {
+        match kinds {
+            Some(kinds) => serde_json::json!({ "arity": kinds.len(), "kinds": kinds }),
+            None => serde_json::Value::Null,
+        }
+    }
+
+    let info = serde_json::json!({
+        "protocol": PROTOCOL_VERSION,
+        "app": app,
+        "work_item": args_json(In::arg_kinds()),
+        "result": args_json(Out::arg_kinds()),
+    });
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+/// Runs the step of the app given by the command line: `split`, `exec` or
+/// `merge`, or `info`, which prints the protocol version and the args of work
+/// items and results as JSON for the runner to check before a run.
 pub fn run<
     S: Splitter<WorkItem = In>,
     E: Executor<S::WorkItem, Out>,
-    M: Merger<In, Out>,
+    M: Merger<In, Out, R>,
     Out: IntoTaskDef + FromTaskDef,
+    R: FromTaskOutput<Out>,
     In: IntoTaskDef + FromTaskDef,
 >(
     splitter: S,
     executor: E,
     merger: M,
 ) -> Result<(), DynError> {
-    let mut args: Vec<String> = env::args().collect();
-    // TODO: check param len
-    let command = args[1].clone();
+    dispatch(None, splitter, executor, merger)
+}
 
-    // Remove program name and command.
-    args.drain(0..2);
+/// Same as `run`, with the app name and version reported by `info`.
+pub fn run_app<
+    S: Splitter<WorkItem = In>,
+    E: Executor<S::WorkItem, Out>,
+    M: Merger<In, Out, R>,
+    Out: IntoTaskDef + FromTaskDef,
+    R: FromTaskOutput<Out>,
+    In: IntoTaskDef + FromTaskDef,
+>(
+    app: AppInfo,
+    splitter: S,
+    executor: E,
+    merger: M,
+) -> Result<(), DynError> {
+    dispatch(Some(&app), splitter, executor, merger)
+}
+
+fn dispatch<
+    S: Splitter<WorkItem = In>,
+    E: Executor<S::WorkItem, Out>,
+    M: Merger<In, Out, R>,
+    Out: IntoTaskDef + FromTaskDef,
+    R: FromTaskOutput<Out>,
+    In: IntoTaskDef + FromTaskDef,
+>(
+    app: Option<&AppInfo>,
+    splitter: S,
+    executor: E,
+    merger: M,
+) -> Result<(), DynError> {
+    // Skip program name.
+    let mut args = env::args().skip(1);
+    let command = args.next().ok_or(ApiError::MissingCommand)?;
+    let args: Vec<String> = args.collect();
 
     if command == "split" {
         split_step(splitter, &args)
@@ -151,6 +325,8 @@ pub fn run<
         execute_step(executor, &args)
     } else if command == "merge" {
         merge_step(merger, &args)
+    } else if command == "info" {
+        info_step::<In, Out>(app)
     } else {
         Err(ApiError::NoCommand { command }.into())
     }
@@ -163,10 +339,10 @@ pub fn run<
 #[allow(unused)]
 mod test {
 
-    use super::{execute_step, load_from, save_to, split_step};
+    use super::{execute_step, load_from, merge_step, save_to, split_step, PROTOCOL_VERSION};
     use crate::blob::{Blob, Output};
     use crate::splitter::SplitContext;
-    use crate::taskdef::{TaskArg, TaskDef};
+    use crate::taskdef::{ArgKind, FromTaskDef, TaskArg, TaskDef, TaskFile};
     use serde_json;
     use std::fs;
     use std::path::PathBuf;
@@ -213,11 +389,21 @@ mod test {
         split_step(&splitter1, &vec![test_dir.to_str().unwrap().to_owned()]).unwrap();
 
         let tasks_defs_file = test_dir.clone().join("tasks.json");
-        let tasks_defs: Vec<TaskDef> = load_from(&tasks_defs_file).unwrap();
+        let tasks_file: TaskFile<Vec<TaskDef>> = load_from(&tasks_defs_file).unwrap();
+        assert_eq!(tasks_file.version, PROTOCOL_VERSION);
+        let tasks_defs = tasks_file.data;
 
         // Two subtasks
         assert_eq!(tasks_defs.len(), 2);
 
+        // Also streamed, one per line
+        let stream = fs::read_to_string(test_dir.join("tasks.ndjson")).unwrap();
+        let streamed = stream
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect::<Vec<TaskDef>>();
+        assert_eq!(streamed, tasks_defs);
+
         // Each subtasks has one element of type Meta
         assert_eq!(tasks_defs[0].0.len(), 1);
         assert_eq!(tasks_defs[1].0.len(), 1);
@@ -240,7 +426,9 @@ mod test {
         split_step(&splitter2, &vec![test_dir.to_str().unwrap().to_owned()]).unwrap();
 
         let tasks_defs_file = test_dir.clone().join("tasks.json");
-        let tasks_defs = load_from::<Vec<TaskDef>>(&tasks_defs_file).unwrap();
+        let tasks_defs = load_from::<TaskFile<Vec<TaskDef>>>(&tasks_defs_file)
+            .unwrap()
+            .data;
 
         // One subtask two elements
         assert_eq!(tasks_defs.len(), 1);
@@ -273,8 +461,31 @@ mod test {
 
         execute_step(
             &execute1,
-            &vec![tasks_defs_file.to_str().unwrap().to_owned(), out_file],
+            &vec![
+                tasks_defs_file.to_str().unwrap().to_owned(),
+                out_file.clone(),
+            ],
         )
         .unwrap();
+        // Runners writing bare task files get a bare answer.
+        let output: serde_json::Value = load_from(&PathBuf::from(out_file)).unwrap();
+        assert_eq!(output, serde_json::json!([{"meta": 3}]));
+    }
+
+    #[test]
+    fn test_missing_args() {
+        assert!(split_step(splitter1, &[]).is_err());
+        assert!(execute_step(execute1, &["task.json".to_owned()]).is_err());
+        let merger = |_: &Vec<String>, _: Vec<((u32,), (u32,))>| ();
+        let args = ["tasks.json".to_owned(), "outputs.json".to_owned()];
+        assert!(merge_step(merger, &args).is_err());
+    }
+
+    #[test]
+    fn test_arg_kinds() {
+        assert_eq!(
+            <(u32, Output, Blob)>::arg_kinds(),
+            Some(vec![ArgKind::Meta, ArgKind::Output, ArgKind::Blob])
+        );
     }
 }
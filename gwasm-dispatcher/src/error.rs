@@ -1,3 +1,5 @@
+use crate::taskdef::ArgKind;
+use serde::{Deserialize, Serialize};
 use std::{error::Error as StdErr, fmt, io, path};
 
 pub(crate) type DynError = Box<dyn StdErr>;
@@ -16,6 +18,9 @@ pub enum Error {
     //#[fail(display = "{}", _0)]
     Json(serde_json::error::Error),
 
+    //#[fail(display = "{}", _0)]
+    Bincode(bincode::Error),
+
     //#[fail(display = "invalid arg")]
     MetaExpected,
 
@@ -24,6 +29,34 @@ pub enum Error {
 
     //#[fail(display = "Expected output entry.")]
     OutputExpected,
+
+    //#[fail(display = "Expected blob dir entry.")]
+    BlobDirExpected,
+
+    //#[fail(display = "Expected output dir entry.")]
+    OutputDirExpected,
+
+    //#[fail(display = "expected {} task args, found {}", expected, found)]
+    ArgCount {
+        expected: usize,
+        found: usize,
+    },
+
+    //#[fail(display = "task arg {}: expected {}, found {}", index, expected, found)]
+    ArgKindMismatch {
+        index: usize,
+        expected: ArgKind,
+        found: ArgKind,
+    },
+
+    //#[fail(display = "unsupported task format version {}", _0)]
+    UnsupportedVersion(u32),
+
+    //#[fail(display = "task failed: {}", _0)]
+    TaskFailed(TaskError),
+
+    //#[fail(display = "corrupted blob {}", _0)]
+    BlobMismatch(String),
 }
 
 impl StdErr for Error {
@@ -32,6 +65,7 @@ impl StdErr for Error {
             Error::IO(e) => Some(e),
             Error::Prefix(e) => Some(e),
             Error::Json(e) => Some(e),
+            Error::Bincode(e) => Some(e),
             _ => None,
         }
     }
@@ -44,13 +78,55 @@ impl fmt::Display for Error {
             Self::Prefix(e) => e.fmt(f),
             Self::InvalidPath(msg) => write!(f, "invalid path: {}", msg),
             Self::Json(e) => e.fmt(f),
+            Self::Bincode(e) => e.fmt(f),
             Self::MetaExpected => write!(f, "invalid arg"),
             Self::BlobExpected => write!(f, "Expected blob entry."),
             Self::OutputExpected => write!(f, "Expected output entry."),
+            Self::BlobDirExpected => write!(f, "Expected blob dir entry."),
+            Self::OutputDirExpected => write!(f, "Expected output dir entry."),
+            Self::ArgCount { expected, found } => {
+                write!(f, "expected {} task args, found {}", expected, found)
+            }
+            Self::ArgKindMismatch {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "task arg {}: expected {}, found {}",
+                index, expected, found
+            ),
+            Self::UnsupportedVersion(version) => {
+                write!(f, "unsupported task format version {}", version)
+            }
+            Self::TaskFailed(e) => write!(f, "task failed: {}", e),
+            Self::BlobMismatch(msg) => write!(f, "corrupted blob {}", msg),
         }
     }
 }
 
+/// Failure reported by an executor, stored in place of the task output.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskError {
+    pub message: String,
+}
+
+impl TaskError {
+    pub fn new(message: impl fmt::Display) -> Self {
+        TaskError {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl StdErr for TaskError {}
+
 impl Error {
     pub fn invalid_path(path: &path::Path) -> Self {
         Error::InvalidPath(path.display().to_string())
@@ -72,5 +148,6 @@ macro_rules! map_error {
 map_error! {
     io::Error => IO,
     path::StripPrefixError => Prefix,
-    serde_json::error::Error => Json
+    serde_json::error::Error => Json,
+    bincode::Error => Bincode
 }
@@ -1,13 +1,209 @@
-use crate::error::Error;
-use crate::taskdef::{FromTaskArg, FromTaskDef, IntoTaskDef, TaskDef};
-use std::path::Path;
+use crate::error::{Error, TaskError};
+use crate::merger::WithContext;
+use crate::progress;
+use crate::taskdef::{FromTaskArg, FromTaskDef, IntoTaskDef, TaskArg, TaskDef};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Provides execution context on exec stage.
+///
+/// Taken by executors wrapped in `WithContext`, before the work item.
+pub trait ExecContext {
+    /// Index of the subtask, as numbered by the runner.
+    fn index(&self) -> usize;
+
+    /// Seed derived from the subtask index and its input.
+    ///
+    /// Stays the same when the subtask is retried or runs on another
+    /// provider, so random sampling can be reproduced.
+    fn seed(&self) -> u64;
+
+    /// Directory for temporary files, removed once the executor returns.
+    fn scratch_dir(&mut self) -> Result<PathBuf, Error>;
+
+    /// Reports progress of the subtask, `fraction` going from 0 to 1.
+    fn progress(&mut self, fraction: f64, message: &str);
+}
+
+struct TaskDirCtx {
+    index: usize,
+    seed: u64,
+    scratch_dir: PathBuf,
+    output_dir: PathBuf,
+}
+
+impl ExecContext for TaskDirCtx {
+    fn index(&self) -> usize {
+        self.index
+    }
+
+    fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    fn scratch_dir(&mut self) -> Result<PathBuf, Error> {
+        fs::create_dir_all(&self.scratch_dir)?;
+        Ok(self.scratch_dir.clone())
+    }
+
+    fn progress(&mut self, fraction: f64, message: &str) {
+        progress::report(&self.output_dir, fraction, message)
+    }
+}
+
+/// Removes the progress file and the scratch dir from the output dir once
+/// the executor returns, so they never end up among the outputs.
+///
+/// Panics only unwind, and so clean up, in native builds such as `testing`.
+/// Wasm guests abort on panic and leave both behind, in the output dir of a
+/// subtask that failed anyway.
+struct ExecCleanup<'a> {
+    output_dir: &'a Path,
+    scratch_dir: PathBuf,
+}
+
+impl ExecCleanup<'_> {
+    fn clean(&self) -> Result<(), Error> {
+        progress::clear(self.output_dir);
+        if self.scratch_dir.exists() {
+            fs::remove_dir_all(&self.scratch_dir)?;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        self.clean()
+    }
+}
+
+impl Drop for ExecCleanup<'_> {
+    fn drop(&mut self) {
+        let _ = self.clean();
+    }
+}
+
+/// Hashes the index and the inputs of a task, leaving out output paths, which
+/// differ between runners.
+fn task_seed(index: usize, task: &TaskDef) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update((index as u64).to_le_bytes());
+    for arg in &task.0 {
+        match arg {
+            TaskArg::Meta(value) => hasher.update(value.to_string()),
+            TaskArg::Blob(blob) | TaskArg::BlobDir(blob) => {
+                hasher.update(blob.sha256.as_ref().unwrap_or(&blob.path))
+            }
+            _ => (),
+        }
+        hasher.update([0]);
+    }
+    let mut seed = [0; 8];
+    seed.copy_from_slice(&hasher.finalize()[..8]);
+    u64::from_le_bytes(seed)
+}
 
 pub trait Executor<In: FromTaskDef, Out: IntoTaskDef> {
-    fn exec(&self, task: In) -> Out;
+    /// Runs the task, returning its output and the work items it spawned.
+    fn exec_spawn(
+        &self,
+        context: &mut dyn ExecContext,
+        task: In,
+    ) -> (Result<Out, TaskError>, Vec<In>);
+
+    fn exec(&self, context: &mut dyn ExecContext, task: In) -> Result<Out, TaskError> {
+        self.exec_spawn(context, task).0
+    }
+}
+
+/// Executor output together with new work items.
+///
+/// Spawned items run as further subtasks of the same app; the merger gets them
+/// as children of this task through `MergeContext::spawned` or `Tree`. Their
+/// blobs must be files written by this executor, e.g. through `Output` args.
+pub struct Spawn<Out, W> {
+    pub output: Out,
+    pub work: Vec<W>,
 }
 
-pub(crate) fn exec_for<In: FromTaskDef, Out: IntoTaskDef, E: Executor<In, Out>>(
+/// Value returned by an executor function taking `W`: its output,
+/// `Result<Out, E>` for fallible executors, or `Spawn<Out, W>`.
+///
+/// An `Err` is stored as the task output and handed to the merger as a `TaskError`.
+pub trait ExecOutput<W> {
+    type Output: IntoTaskDef;
+
+    fn into_exec_result(self) -> (Result<Self::Output, TaskError>, Vec<W>);
+}
+
+impl<W, Out: IntoTaskDef> ExecOutput<W> for Out {
+    type Output = Out;
+
+    fn into_exec_result(self) -> (Result<Out, TaskError>, Vec<W>) {
+        (Ok(self), Vec::new())
+    }
+}
+
+impl<W, Out: IntoTaskDef, E: Display> ExecOutput<W> for Result<Out, E> {
+    type Output = Out;
+
+    fn into_exec_result(self) -> (Result<Out, TaskError>, Vec<W>) {
+        (self.map_err(TaskError::new), Vec::new())
+    }
+}
+
+impl<W, Out: IntoTaskDef> ExecOutput<W> for Spawn<Out, W> {
+    type Output = Out;
+
+    fn into_exec_result(self) -> (Result<Out, TaskError>, Vec<W>) {
+        (Ok(self.output), self.work)
+    }
+}
+
+impl<W, Out: IntoTaskDef, E: Display> ExecOutput<W> for Result<Spawn<Out, W>, E> {
+    type Output = Out;
+
+    fn into_exec_result(self) -> (Result<Out, TaskError>, Vec<W>) {
+        match self {
+            Ok(spawn) => spawn.into_exec_result(),
+            Err(e) => (Err(TaskError::new(e)), Vec::new()),
+        }
+    }
+}
+
+/// Work item passed to an executor as a single argument.
+///
+/// Implemented by `#[derive(TaskDef)]`, tuples are spread over executor arguments instead.
+pub trait TaskInput: FromTaskDef {}
+
+impl<In: TaskInput, R: ExecOutput<In>, F: Fn(In) -> R> Executor<In, R::Output> for F {
+    fn exec_spawn(
+        &self,
+        _context: &mut dyn ExecContext,
+        task: In,
+    ) -> (Result<R::Output, TaskError>, Vec<In>) {
+        self(task).into_exec_result()
+    }
+}
+
+/// Executor taking the `ExecContext` before the whole work item,
+/// e.g. `WithContext(|ctx: &mut dyn ExecContext, (samples,): (u32,)| ...)`.
+impl<In: FromTaskDef, R: ExecOutput<In>, F: Fn(&mut dyn ExecContext, In) -> R>
+    Executor<In, R::Output> for WithContext<F>
+{
+    fn exec_spawn(
+        &self,
+        context: &mut dyn ExecContext,
+        task: In,
+    ) -> (Result<R::Output, TaskError>, Vec<In>) {
+        (self.0)(context, task).into_exec_result()
+    }
+}
+
+pub(crate) fn exec_for<In: FromTaskDef + IntoTaskDef, Out: IntoTaskDef, E: Executor<In, Out>>(
     executor: &E,
+    index: usize,
     task_input: TaskDef,
     task_input_dir: &Path,
     task_output_dir: &Path,
@@ -15,21 +211,44 @@ pub(crate) fn exec_for<In: FromTaskDef, Out: IntoTaskDef, E: Executor<In, Out>>(
     let in_dir_str = task_input_dir.display().to_string();
     let out_dir_str = format!("{}/", task_output_dir.display());
 
-    let input = In::from_task_def(
-        task_input.rebase_output(&in_dir_str, &out_dir_str),
-        task_input_dir,
-    )?;
-    executor.exec(input).into_task_def(task_output_dir)
+    let mut context = TaskDirCtx {
+        index,
+        seed: task_seed(index, &task_input),
+        scratch_dir: task_output_dir.join(".scratch"),
+        output_dir: task_output_dir.to_owned(),
+    };
+    // Outputs lose their digests here, as they are yet to be written.
+    let task_input = task_input.rebase_output(&in_dir_str, &out_dir_str);
+    task_input.verify_blobs(task_input_dir)?;
+    let input = In::from_task_def(task_input, task_input_dir)?;
+    let cleanup = ExecCleanup {
+        output_dir: task_output_dir,
+        scratch_dir: context.scratch_dir.clone(),
+    };
+    let (output, work) = executor.exec_spawn(&mut context, input);
+    cleanup.finish()?;
+    let mut task_output = match output {
+        Ok(output) => output.into_task_def(task_output_dir)?,
+        Err(e) => TaskDef::failed(e),
+    };
+    if !work.is_empty() {
+        let work = work
+            .into_iter()
+            .map(|item| item.into_task_def(task_output_dir))
+            .collect::<Result<_, _>>()?;
+        task_output.0.push(TaskArg::Spawn(work));
+    }
+    Ok(task_output)
 }
 
 macro_rules! gen_bind {
     (
         $($t : ident = $e : ident),+
     ) => {
-           impl<$($t : FromTaskArg,)+  Out : IntoTaskDef, F : Fn($($t),+) -> Out> Executor<($($t,)+), Out> for F {
-                fn exec(&self, task: ($($t,)+)) -> Out {
+           impl<$($t : FromTaskArg,)+  R : ExecOutput<($($t,)+)>, F : Fn($($t),+) -> R> Executor<($($t,)+), R::Output> for F {
+                fn exec_spawn(&self, _context: &mut dyn ExecContext, task: ($($t,)+)) -> (Result<R::Output, TaskError>, Vec<($($t,)+)>) {
                     let ($($e,)+) = task;
-                    self($($e),+)
+                    self($($e),+).into_exec_result()
                 }
             }
     };
@@ -55,18 +274,145 @@ mod test {
         (v1 + v2,)
     }
 
+    #[derive(gwasm_dispatcher_derive::TaskDef)]
+    struct Pair {
+        v1: u32,
+        v2: u32,
+    }
+
+    #[derive(gwasm_dispatcher_derive::TaskOutput)]
+    struct Sum {
+        sum: u32,
+    }
+
+    fn add_pair(pair: Pair) -> Sum {
+        Sum {
+            sum: pair.v1 + pair.v2,
+        }
+    }
+
+    #[test]
+    fn test_exec_struct() {
+        let task: TaskDef = serde_json::from_str(r#"[{"meta": 10},{"meta": 15}]"#).unwrap();
+        let ret = exec_for(&add_pair, 0, task, &PathBuf::from("."), ".".as_ref()).unwrap();
+        assert_eq!(serde_json::to_string(&ret).unwrap(), r#"[{"meta":25}]"#);
+    }
+
+    fn checked_div(v1: u32, v2: u32) -> Result<(u32,), String> {
+        v1.checked_div(v2)
+            .map(|v| (v,))
+            .ok_or_else(|| format!("cannot divide {} by zero", v1))
+    }
+
+    #[test]
+    fn test_exec_failure() {
+        let task: TaskDef = serde_json::from_str(r#"[{"meta": 10},{"meta": 0}]"#).unwrap();
+        let ret = exec_for(&checked_div, 0, task, &PathBuf::from("."), ".".as_ref()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&ret).unwrap(),
+            r#"[{"error":{"message":"cannot divide 10 by zero"}}]"#
+        );
+        assert_eq!(
+            ret.failure().map(|e| e.message.as_str()),
+            Some("cannot divide 10 by zero")
+        );
+    }
+
+    fn bisect(lo: u32, hi: u32) -> Spawn<(u32,), (u32, u32)> {
+        let work = if hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            vec![(lo, mid), (mid, hi)]
+        } else {
+            Vec::new()
+        };
+        Spawn {
+            output: (hi - lo,),
+            work,
+        }
+    }
+
+    #[test]
+    fn test_exec_spawn() {
+        let task: TaskDef = serde_json::from_str(r#"[{"meta": 0},{"meta": 4}]"#).unwrap();
+        let ret = exec_for(&bisect, 0, task, &PathBuf::from("."), ".".as_ref()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&ret).unwrap(),
+            r#"[{"meta":4},{"spawn":[[{"meta":0},{"meta":2}],[{"meta":2},{"meta":4}]]}]"#
+        );
+        assert_eq!(ret.spawned().len(), 2);
+
+        let task: TaskDef = serde_json::from_str(r#"[{"meta": 2},{"meta": 3}]"#).unwrap();
+        let ret = exec_for(&bisect, 0, task, &PathBuf::from("."), ".".as_ref()).unwrap();
+        assert_eq!(serde_json::to_string(&ret).unwrap(), r#"[{"meta":1}]"#);
+    }
+
     #[test]
     fn test_exec() {
         let task: TaskDef = serde_json::from_str(r#"[{"meta": 10}]"#).unwrap();
 
-        let (v,) = Executor::exec(&inc_v, (0u32,));
+        let mut ctx = TaskDirCtx {
+            index: 0,
+            seed: 0,
+            scratch_dir: PathBuf::from(".scratch"),
+            output_dir: PathBuf::from("."),
+        };
+        let (v,) = Executor::exec(&inc_v, &mut ctx, (0u32,)).unwrap();
 
         assert_eq!(v, 1);
-        let ret = exec_for(&inc_v, task, &PathBuf::from("."), ".".as_ref()).unwrap();
+        let ret = exec_for(&inc_v, 0, task, &PathBuf::from("."), ".".as_ref()).unwrap();
         eprintln!("{}", serde_json::to_string(&ret).unwrap());
 
         let task: TaskDef = serde_json::from_str(r#"[{"meta": 10},{"meta": 15}]"#).unwrap();
-        let ret = exec_for(&add_me, task, &PathBuf::from("."), ".".as_ref()).unwrap();
+        let ret = exec_for(&add_me, 0, task, &PathBuf::from("."), ".".as_ref()).unwrap();
         eprintln!("{}", serde_json::to_string(&ret).unwrap());
     }
+
+    #[test]
+    fn test_exec_context() {
+        let out_dir = std::env::temp_dir().join("gwasm-dispatcher-test_exec_context");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let executor = WithContext(|ctx: &mut dyn ExecContext, (v,): (u32,)| {
+            let scratch_dir = ctx.scratch_dir().unwrap();
+            std::fs::write(scratch_dir.join("tmp.bin"), b"tmp").unwrap();
+            ctx.progress(0.5, "halfway");
+            assert_eq!(
+                crate::progress::Progress::read(scratch_dir.parent().unwrap()).map(|p| p.fraction),
+                Some(0.5)
+            );
+            (v, ctx.index(), ctx.seed())
+        });
+        let run = |index| {
+            let task: TaskDef = serde_json::from_str(r#"[{"meta": 7}]"#).unwrap();
+            let ret = exec_for(&executor, index, task, ".".as_ref(), &out_dir).unwrap();
+            <(u32, usize, u64)>::from_task_def(ret, &out_dir).unwrap()
+        };
+
+        let (v, index, seed) = run(3);
+        assert_eq!((v, index), (7, 3));
+        assert!(!out_dir.join(".scratch").exists());
+        assert!(!out_dir.join(crate::progress::PROGRESS_FILE).exists());
+        // Retries get the same seed, other subtasks another one.
+        assert_eq!(run(3).2, seed);
+        assert_ne!(run(4).2, seed);
+    }
+
+    #[test]
+    fn test_exec_panic() {
+        let out_dir = std::env::temp_dir().join("gwasm-dispatcher-test_exec_panic");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let executor = WithContext(|ctx: &mut dyn ExecContext, (_v,): (u32,)| -> (u32,) {
+            let scratch_dir = ctx.scratch_dir().unwrap();
+            std::fs::write(scratch_dir.join("tmp.bin"), b"tmp").unwrap();
+            ctx.progress(0.5, "halfway");
+            panic!("executor failed");
+        });
+        let task: TaskDef = serde_json::from_str(r#"[{"meta": 7}]"#).unwrap();
+        let result = std::panic::catch_unwind(|| {
+            exec_for(&executor, 0, task, ".".as_ref(), &out_dir).unwrap();
+        });
+
+        assert!(result.is_err());
+        assert!(!out_dir.join(".scratch").exists());
+        assert!(!out_dir.join(crate::progress::PROGRESS_FILE).exists());
+    }
 }
@@ -1,37 +1,91 @@
 #![allow(clippy::needless_doctest_main)]
 //! gWASM Runner API for RUST.
 //!
+//! An app passes its splitter, executor and merger to `dispatcher::run` in
+//! its `main`, which runs the step the runner asks for. Work items and
+//! outputs are tuples of task args, or structs with the `derive` feature.
+//!
 //! ## Examples
 //!
+//! The `testing` module runs all steps of an app natively:
+//!
 //! ```edition2018
-//! use gwasm_dispatcher::{dispatcher, SplitContext};
+//! use gwasm_dispatcher::{testing, SplitContext};
 //!
-//! fn main() {
-//!     dispatcher::run(
-//!         move |_: &mut dyn SplitContext| {
-//!             const NUM_SUBTASKS: usize = 10;
-//!             let arr: Vec<u64> = (1..=100).collect();
-//!             arr.chunks(NUM_SUBTASKS)
-//!                 .map(|x| (x.to_vec(),))
-//!                 .collect::<Vec<_>>()
-//!         },
-//!         |task: Vec<u64>| (task.into_iter().sum(),),
-//!         |_: &Vec<String>, results: Vec<(_, _)>| {
-//!             let given: u64 = results.iter().map(|(_, (result,))| result).sum();
-//!             let expected: u64 = (1..=100).sum();
-//!             assert_eq!(expected, given, "sums should be equal")
-//!         },
-//!     )
-//!         .unwrap()
-//! }
+//! testing::run(
+//!     &[],
+//!     move |_: &mut dyn SplitContext| {
+//!         const NUM_SUBTASKS: usize = 10;
+//!         let arr: Vec<u64> = (1..=100).collect();
+//!         arr.chunks(NUM_SUBTASKS)
+//!             .map(|x| (x.to_vec(),))
+//!             .collect::<Vec<_>>()
+//!     },
+//!     |task: Vec<u64>| (task.into_iter().sum(),),
+//!     |_: &Vec<String>, results: Vec<(_, _)>| {
+//!         let given: u64 = results.iter().map(|(_, (result,))| result).sum();
+//!         let expected: u64 = (1..=100).sum();
+//!         assert_eq!(expected, given, "sums should be equal")
+//!     },
+//! )
+//! .unwrap();
 //! ```
 //!
 //!
 pub mod dispatcher;
+pub mod progress;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod testing;
+
+// Lets derive generated code refer to `::gwasm_dispatcher` inside this crate.
+extern crate self as gwasm_dispatcher;
 
-pub use crate::blob::{Blob, Output};
+pub use crate::blob::{Blob, BlobDir, Output, OutputDir, TypedBlob};
 pub use crate::dispatcher::TaskResult;
-pub use crate::splitter::SplitContext;
+pub use crate::error::{Error, TaskError};
+pub use crate::executor::{ExecContext, ExecOutput, Spawn, TaskInput};
+pub use crate::merger::{
+    IntoMergeOutcome, MergeContext, MergeOutcome, TaskTree, Tree, WithContext,
+};
+pub use crate::splitter::{streaming, SplitContext, Streaming};
+/// Makes a struct with named fields a work item, one task arg per field.
+///
+/// ```edition2018
+/// use gwasm_dispatcher::{testing, SplitContext, TaskDef, TaskOutput};
+///
+/// #[derive(TaskDef)]
+/// struct Chunk {
+///     numbers: Vec<u64>,
+/// }
+///
+/// #[derive(TaskOutput)]
+/// struct ChunkSum {
+///     sum: u64,
+/// }
+///
+/// testing::run(
+///     &[],
+///     |_: &mut dyn SplitContext| {
+///         vec![
+///             Chunk { numbers: (1..=50).collect() },
+///             Chunk { numbers: (51..=100).collect() },
+///         ]
+///     },
+///     |chunk: Chunk| ChunkSum {
+///         sum: chunk.numbers.into_iter().sum(),
+///     },
+///     |_: &Vec<String>, results: Vec<(Chunk, ChunkSum)>| {
+///         let given: u64 = results.iter().map(|(_, result)| result.sum).sum();
+///         assert_eq!(given, 5050);
+///     },
+/// )
+/// .unwrap();
+/// ```
+#[cfg(feature = "derive")]
+pub use gwasm_dispatcher_derive::TaskDef;
+/// Makes a struct with named fields an executor output, see [`TaskDef`](derive@TaskDef).
+#[cfg(feature = "derive")]
+pub use gwasm_dispatcher_derive::TaskOutput;
 
 mod blob;
 mod error;
@@ -41,4 +95,7 @@ mod executor;
 mod merger;
 mod splitter;
 
-pub use taskdef::{TaskArg, TaskDef};
+pub use taskdef::{
+    ArgKind, FromTaskArg, FromTaskDef, FromTaskOutput, IntoTaskArg, IntoTaskDef, TaskArg, TaskDef,
+    TaskFile,
+};
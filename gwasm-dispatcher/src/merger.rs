@@ -1,39 +1,380 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::error::DynError;
-use crate::taskdef::{FromTaskDef, TaskDef};
+use crate::blob::Output;
+use crate::error::{DynError, Error};
+use crate::progress;
+use crate::splitter::{SplitContext, WorkDirCtx};
+use crate::taskdef::{FromTaskDef, FromTaskOutput, IntoTaskDef, TaskDef};
 
-pub trait Merger<In: FromTaskDef, Out: FromTaskDef> {
-    fn merge(self, args_vec: &[String], tasks: Vec<(In, Out)>);
+/// Provides execution context on merge stage.
+pub trait MergeContext {
+    /// Command line arguments passed to runner.
+    fn args(&self) -> &Vec<String>;
+
+    /// Allocates a result file with the given name.
+    ///
+    /// The runner copies result files into its `--output-dir` once merge is done.
+    fn result_file(&mut self, name: &str) -> Result<Output, Error>;
+
+    /// Allocates new output file for work items of the next round.
+    fn new_blob(&mut self) -> Output;
+
+    /// Indexes of the tasks spawned by the task at `idx`.
+    fn spawned(&self, idx: usize) -> &[usize];
+
+    /// Reports progress of the merge, `fraction` going from 0 to 1.
+    fn progress(&mut self, fraction: f64, message: &str);
+}
+
+struct ResultDirCtx {
+    work_dir: PathBuf,
+    result_dir: PathBuf,
+    next_dir: PathBuf,
+    next: WorkDirCtx,
+    spawned: Vec<Vec<usize>>,
+}
+
+impl MergeContext for ResultDirCtx {
+    fn args(&self) -> &Vec<String> {
+        self.next.args()
+    }
+
+    fn spawned(&self, idx: usize) -> &[usize] {
+        self.spawned.get(idx).map_or(&[], Vec::as_slice)
+    }
+
+    fn progress(&mut self, fraction: f64, message: &str) {
+        progress::report(&self.work_dir, fraction, message)
+    }
+
+    fn new_blob(&mut self) -> Output {
+        // A failure surfaces when the output is opened.
+        let _ = std::fs::create_dir_all(&self.next_dir);
+        self.next.new_blob()
+    }
+
+    fn result_file(&mut self, name: &str) -> Result<Output, Error> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(&['/', '\\'][..]) {
+            return Err(Error::invalid_path(Path::new(name)));
+        }
+        std::fs::create_dir_all(&self.result_dir)?;
+        Ok(Output(self.result_dir.join(name)))
+    }
+}
+
+/// Decides whether the run ends with this merge.
+#[derive(Debug)]
+pub enum MergeOutcome<W> {
+    /// The run is finished.
+    Done,
+    /// Run another split-exec-merge round over the given work items.
+    ///
+    /// Blobs of these items must be allocated with `MergeContext::new_blob`.
+    Continue(Vec<W>),
+}
+
+/// Value returned by a merger function: `()` for single round apps, or `MergeOutcome`.
+pub trait IntoMergeOutcome<W> {
+    fn into_merge_outcome(self) -> MergeOutcome<W>;
+}
+
+impl<W> IntoMergeOutcome<W> for () {
+    fn into_merge_outcome(self) -> MergeOutcome<W> {
+        MergeOutcome::Done
+    }
+}
+
+impl<W> IntoMergeOutcome<W> for MergeOutcome<W> {
+    fn into_merge_outcome(self) -> MergeOutcome<W> {
+        self
+    }
+}
+
+/// Receives every work item with its output.
+///
+/// `R` is either the executor output itself, which fails the merge when any
+/// subtask failed, or `Result<Out, TaskError>` to handle failures in the merger.
+pub trait Merger<In: FromTaskDef, Out: FromTaskDef, R: FromTaskOutput<Out> = Out> {
+    fn merge(self, context: &mut dyn MergeContext, tasks: Vec<(In, R)>) -> MergeOutcome<In>;
+}
+
+/// Merger taking the `MergeContext` instead of the arguments.
+///
+/// e.g. `WithContext(|ctx: &mut dyn MergeContext, results: Vec<(_, _)>| ...)`
+///
+/// Also wraps executors taking the `ExecContext`.
+pub struct WithContext<F>(pub F);
+
+/// A task with the tasks it spawned.
+#[derive(Debug)]
+pub struct TaskTree<In, R> {
+    pub input: In,
+    pub output: R,
+    pub children: Vec<TaskTree<In, R>>,
 }
 
-pub(crate) fn merge_for<M: Merger<In, Out>, In: FromTaskDef, Out: FromTaskDef>(
+/// Merger taking tasks as trees of spawned tasks, rooted at split work items.
+///
+/// e.g. `Tree(|ctx: &mut dyn MergeContext, roots: Vec<TaskTree<_, _>>| ...)`
+pub struct Tree<F>(pub F);
+
+fn build_trees<In, R>(context: &dyn MergeContext, tasks: Vec<(In, R)>) -> Vec<TaskTree<In, R>> {
+    let mut is_child = vec![false; tasks.len()];
+    for idx in 0..tasks.len() {
+        for &child in context.spawned(idx) {
+            if let Some(flag) = is_child.get_mut(child) {
+                *flag = true;
+            }
+        }
+    }
+
+    let mut slots: Vec<_> = tasks.into_iter().map(Some).collect();
+    (0..slots.len())
+        .filter(|&idx| !is_child[idx])
+        .filter_map(|idx| take_tree(context, &mut slots, idx))
+        .collect()
+}
+
+fn take_tree<In, R>(
+    context: &dyn MergeContext,
+    slots: &mut [Option<(In, R)>],
+    idx: usize,
+) -> Option<TaskTree<In, R>> {
+    let (input, output) = slots.get_mut(idx)?.take()?;
+    let children = context
+        .spawned(idx)
+        .iter()
+        .filter_map(|&child| take_tree(context, slots, child))
+        .collect();
+    Some(TaskTree {
+        input,
+        output,
+        children,
+    })
+}
+
+pub(crate) fn merge_for<
+    M: Merger<In, Out, R>,
+    In: FromTaskDef + IntoTaskDef,
+    Out: FromTaskDef,
+    R: FromTaskOutput<Out>,
+>(
     merger: M,
     args_vec: &[String],
     in_outs_pack: Vec<(TaskDef, TaskDef)>,
     split_dir: &Path,
     exec_dir: &Path,
-) -> Result<(), DynError> {
-    let in_outs: Result<Vec<(In, Out)>, DynError> = in_outs_pack
+) -> Result<MergeOutcome<TaskDef>, DynError> {
+    let mut spawned = Vec::with_capacity(in_outs_pack.len());
+    let in_outs: Result<Vec<(In, R)>, DynError> = in_outs_pack
         .into_iter()
-        .map(|(params, output)| -> Result<(In, Out), _> {
+        .map(|(params, mut output)| -> Result<(In, R), _> {
+            spawned.push(output.take_spawned());
+            params.verify_blobs(split_dir)?;
+            output.verify_blobs(exec_dir)?;
             Ok((
                 In::from_task_def(params, split_dir)?,
-                Out::from_task_def(output, exec_dir)?,
+                R::from_task_output(output, exec_dir)?,
             ))
         })
         .collect();
 
-    merger.merge(args_vec, in_outs?);
-    Ok(())
+    let next_dir = exec_dir.join("next");
+    let mut context = ResultDirCtx {
+        work_dir: exec_dir.to_owned(),
+        result_dir: exec_dir.join("results"),
+        next: WorkDirCtx::new(&next_dir, args_vec),
+        next_dir: next_dir.clone(),
+        spawned,
+    };
+    let outcome = merger.merge(&mut context, in_outs?);
+    progress::clear(exec_dir);
+    Ok(match outcome {
+        MergeOutcome::Done => MergeOutcome::Done,
+        MergeOutcome::Continue(items) => MergeOutcome::Continue(
+            items
+                .into_iter()
+                .map(|item| item.into_task_def(&next_dir))
+                .collect::<Result<_, _>>()?,
+        ),
+    })
+}
+
+impl<
+        In: FromTaskDef,
+        Out: FromTaskDef,
+        R: FromTaskOutput<Out>,
+        O: IntoMergeOutcome<In>,
+        F: FnOnce(&Vec<String>, Vec<(In, R)>) -> O,
+    > Merger<In, Out, R> for F
+{
+    fn merge(self, context: &mut dyn MergeContext, tasks: Vec<(In, R)>) -> MergeOutcome<In> {
+        self(context.args(), tasks).into_merge_outcome()
+    }
+}
+
+impl<
+        In: FromTaskDef,
+        Out: FromTaskDef,
+        R: FromTaskOutput<Out>,
+        O: IntoMergeOutcome<In>,
+        F: FnOnce(&mut dyn MergeContext, Vec<(In, R)>) -> O,
+    > Merger<In, Out, R> for WithContext<F>
+{
+    fn merge(self, context: &mut dyn MergeContext, tasks: Vec<(In, R)>) -> MergeOutcome<In> {
+        (self.0)(context, tasks).into_merge_outcome()
+    }
 }
 
-impl<In: FromTaskDef, Out: FromTaskDef, F: FnOnce(&Vec<String>, Vec<(In, Out)>)> Merger<In, Out>
-    for F
+impl<
+        In: FromTaskDef,
+        Out: FromTaskDef,
+        R: FromTaskOutput<Out>,
+        O: IntoMergeOutcome<In>,
+        F: FnOnce(&mut dyn MergeContext, Vec<TaskTree<In, R>>) -> O,
+    > Merger<In, Out, R> for Tree<F>
 {
-    #[allow(clippy::ptr_arg)]
-    fn merge(self, args: &[String], tasks: Vec<(In, Out)>) {
-        let v = args.into();
-        self(&v, tasks);
+    fn merge(self, context: &mut dyn MergeContext, tasks: Vec<(In, R)>) -> MergeOutcome<In> {
+        let roots = build_trees(context, tasks);
+        (self.0)(context, roots).into_merge_outcome()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dispatcher::TaskResult;
+    use crate::error::TaskError;
+
+    fn outputs() -> Vec<(TaskDef, TaskDef)> {
+        serde_json::from_str(
+            r#"[
+                [[{"meta": 1}], [{"meta": 2}]],
+                [[{"meta": 0}], [{"error": {"message": "bad input"}}]]
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_merge_failures() {
+        let mut merged = Vec::new();
+        merge_for(
+            |_: &Vec<String>, tasks: TaskResult<(u32,), Result<(u32,), TaskError>>| merged = tasks,
+            &[],
+            outputs(),
+            ".".as_ref(),
+            ".".as_ref(),
+        )
+        .unwrap();
+        assert_eq!(merged[0], ((1,), Ok((2,))));
+        assert_eq!(merged[1], ((0,), Err(TaskError::new("bad input"))));
+
+        let err = merge_for(
+            |_: &Vec<String>, tasks: Vec<((u32,), (u32,))>| {
+                assert!(tasks.is_empty(), "merged failed task")
+            },
+            &[],
+            outputs(),
+            ".".as_ref(),
+            ".".as_ref(),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "task failed: bad input");
+    }
+
+    #[test]
+    fn test_merge_continue() {
+        let outcome = merge_for(
+            |_: &Vec<String>, tasks: TaskResult<(u32,), Result<(u32,), TaskError>>| {
+                MergeOutcome::Continue(tasks.into_iter().map(|((v,), _)| (v + 1,)).collect())
+            },
+            &[],
+            outputs(),
+            ".".as_ref(),
+            ".".as_ref(),
+        )
+        .unwrap();
+        match outcome {
+            MergeOutcome::Continue(tasks) => assert_eq!(
+                serde_json::to_string(&tasks).unwrap(),
+                r#"[[{"meta":2}],[{"meta":1}]]"#
+            ),
+            MergeOutcome::Done => panic!("expected next round"),
+        }
+
+        let outcome = merge_for(
+            |_: &Vec<String>, _: TaskResult<(u32,), Result<(u32,), TaskError>>| (),
+            &[],
+            outputs(),
+            ".".as_ref(),
+            ".".as_ref(),
+        )
+        .unwrap();
+        assert!(matches!(outcome, MergeOutcome::Done));
+    }
+
+    #[test]
+    fn test_merge_tree() {
+        let in_outs = serde_json::from_str(
+            r#"[
+                [[{"meta": 0}], [{"meta": 10}, {"spawned": [2, 3]}]],
+                [[{"meta": 1}], [{"meta": 11}]],
+                [[{"meta": 2}], [{"meta": 12}, {"spawned": [4]}]],
+                [[{"meta": 3}], [{"meta": 13}]],
+                [[{"meta": 4}], [{"meta": 14}]]
+            ]"#,
+        )
+        .unwrap();
+
+        let mut shape = String::new();
+        merge_for(
+            Tree(
+                |_: &mut dyn MergeContext, roots: Vec<TaskTree<(u32,), (u32,)>>| {
+                    fn show(node: &TaskTree<(u32,), (u32,)>, out: &mut String) {
+                        out.push_str(&format!("{}=>{}(", node.input.0, node.output.0));
+                        node.children.iter().for_each(|child| show(child, out));
+                        out.push(')');
+                    }
+                    roots.iter().for_each(|root| show(root, &mut shape));
+                },
+            ),
+            &[],
+            in_outs,
+            ".".as_ref(),
+            ".".as_ref(),
+        )
+        .unwrap();
+        assert_eq!(shape, "0=>10(2=>12(4=>14())3=>13())1=>11()");
+    }
+
+    #[test]
+    fn test_result_file() {
+        let exec_dir = std::env::temp_dir().join("gwasm-dispatcher-test_result_file");
+        let _ = std::fs::remove_dir_all(&exec_dir);
+        std::fs::create_dir_all(&exec_dir).unwrap();
+
+        merge_for(
+            WithContext(
+                |ctx: &mut dyn MergeContext, tasks: TaskResult<(u32,), Result<(u32,), _>>| {
+                    assert_eq!(ctx.args(), &vec!["sum".to_string()]);
+                    assert!(ctx.result_file("../sum.txt").is_err());
+                    let sum: u32 = tasks.iter().flat_map(|(_, r)| r).map(|(v,)| v).sum();
+                    ctx.result_file("sum.txt")
+                        .unwrap()
+                        .bytes(sum.to_string())
+                        .unwrap();
+                },
+            ),
+            &["sum".to_string()],
+            outputs(),
+            ".".as_ref(),
+            &exec_dir,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(exec_dir.join("results/sum.txt")).unwrap(),
+            "2"
+        );
     }
 }
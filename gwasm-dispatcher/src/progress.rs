@@ -0,0 +1,77 @@
+//! Progress of a running split, exec or merge step.
+//!
+//! Guest code reports it through `progress` of its context. The latest report
+//! is kept in a file in the work dir of the step, where runners poll it.
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Name of the file holding the latest progress, in the dir of split results,
+/// exec outputs or merge inputs.
+pub const PROGRESS_FILE: &str = ".progress.json";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Part of the step done, from 0 to 1.
+    pub fraction: f64,
+    pub message: String,
+}
+
+impl Progress {
+    /// Latest progress reported by the step running in `dir`, if any.
+    pub fn read(dir: &Path) -> Option<Progress> {
+        serde_json::from_slice(&fs::read(dir.join(PROGRESS_FILE)).ok()?).ok()
+    }
+}
+
+/// Replaces the progress of the step running in `dir`.
+///
+/// Written aside and renamed, so runners never read a partial report.
+/// Failures are ignored, as progress is only informative.
+pub(crate) fn report(dir: &Path, fraction: f64, message: &str) {
+    let progress = Progress {
+        fraction: if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0)
+        },
+        message: message.to_owned(),
+    };
+    let tmp_path = dir.join(".progress.tmp");
+    let write = || -> io::Result<()> {
+        fs::write(&tmp_path, serde_json::to_vec(&progress)?)?;
+        fs::rename(&tmp_path, dir.join(PROGRESS_FILE))
+    };
+    let _ = write();
+}
+
+/// Removes the progress file once the step is done.
+pub(crate) fn clear(dir: &Path) {
+    let _ = fs::remove_file(dir.join(PROGRESS_FILE));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let dir = std::env::temp_dir().join("gwasm-dispatcher-test_report");
+        fs::create_dir_all(&dir).unwrap();
+        clear(&dir);
+        assert_eq!(Progress::read(&dir), None);
+
+        report(&dir, 1.5, "rendering");
+        assert_eq!(
+            Progress::read(&dir),
+            Some(Progress {
+                fraction: 1.0,
+                message: "rendering".to_owned()
+            })
+        );
+
+        clear(&dir);
+        assert_eq!(Progress::read(&dir), None);
+    }
+}
@@ -1,6 +1,8 @@
-use crate::blob::{Blob, Output};
+use crate::blob::{Blob, Output, OutputDir};
 use crate::error::Error;
-use crate::taskdef::{FromTaskDef, IntoTaskDef, TaskDef};
+use crate::progress;
+use crate::taskdef::{FromTaskDef, IntoTaskDef, TaskDef, SHARED_DIR};
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 
 /// Provides execution context on split stage.
@@ -11,6 +13,18 @@ pub trait SplitContext {
     /// Allocates new output file.
     fn new_blob(&mut self) -> Output;
 
+    /// Allocates new output file for a blob referenced by many work items.
+    ///
+    /// Backends store it once and hand it to every subtask using it, instead
+    /// of moving or uploading it with each of them.
+    fn new_shared_blob(&mut self) -> Output;
+
+    /// Allocates new output directory, for work items with many input files.
+    fn new_blob_dir(&mut self) -> OutputDir;
+
+    /// Reports progress of the split, `fraction` going from 0 to 1.
+    fn progress(&mut self, fraction: f64, message: &str);
+
     #[doc(hidden)]
     fn blob_from_file(&mut self, path: &Path) -> Result<Blob, Error> {
         self.new_blob().file(path)
@@ -26,6 +40,21 @@ pub trait Splitter {
     type WorkItem: IntoTaskDef + FromTaskDef;
 
     fn split(self, context: &mut dyn SplitContext) -> Vec<Self::WorkItem>;
+
+    /// Hands work items to `emit` one by one, as soon as each is ready.
+    ///
+    /// Backends may start running subtasks before the split is done. By
+    /// default the items returned by `split` are emitted.
+    fn split_each(
+        self,
+        context: &mut dyn SplitContext,
+        emit: &mut dyn FnMut(Self::WorkItem) -> Result<(), Error>,
+    ) -> Result<(), Error>
+    where
+        Self: Sized,
+    {
+        self.split(context).into_iter().try_for_each(emit)
+    }
 }
 
 impl<Out, F: (FnOnce(&mut dyn SplitContext) -> Out)> Splitter for F
@@ -38,53 +67,174 @@ where
     fn split(self, context: &mut dyn SplitContext) -> Vec<Self::WorkItem> {
         self(context).into_iter().collect()
     }
+
+    fn split_each(
+        self,
+        context: &mut dyn SplitContext,
+        emit: &mut dyn FnMut(Self::WorkItem) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        self(context).into_iter().try_for_each(emit)
+    }
+}
+
+/// Splitter passing work items to a callback while it creates them.
+///
+/// Built with [`streaming`].
+pub struct Streaming<F, In> {
+    split: F,
+    _item: PhantomData<fn(In)>,
 }
 
-struct WorkDirCtx {
+/// Wraps a split function that calls `emit` for every work item once its
+/// blobs are written, so subtasks can start before the whole split is done.
+///
+/// Work items emitted after a failure to store one are dropped, and the
+/// failure ends the split step.
+///
+/// ```edition2018
+/// use gwasm_dispatcher::{streaming, testing, Blob, SplitContext};
+///
+/// let splitter = streaming(|ctx: &mut dyn SplitContext, emit: &mut dyn FnMut((Blob, u32))| {
+///     for frame in 0..10 {
+///         let scene = format!("scene {}", frame);
+///         emit((ctx.new_blob().bytes(scene).unwrap(), frame));
+///     }
+/// });
+/// testing::run(
+///     &[],
+///     splitter,
+///     |_scene: Blob, frame: u32| (frame,),
+///     |_: &Vec<String>, results: Vec<(_, (u32,))>| assert_eq!(results.len(), 10),
+/// )
+/// .unwrap();
+/// ```
+pub fn streaming<In, F>(split: F) -> Streaming<F, In>
+where
+    In: IntoTaskDef + FromTaskDef,
+    F: FnOnce(&mut dyn SplitContext, &mut dyn FnMut(In)),
+{
+    Streaming {
+        split,
+        _item: PhantomData,
+    }
+}
+
+impl<In, F> Splitter for Streaming<F, In>
+where
+    In: IntoTaskDef + FromTaskDef,
+    F: FnOnce(&mut dyn SplitContext, &mut dyn FnMut(In)),
+{
+    type WorkItem = In;
+
+    fn split(self, context: &mut dyn SplitContext) -> Vec<In> {
+        let mut items = Vec::new();
+        (self.split)(context, &mut |item| items.push(item));
+        items
+    }
+
+    fn split_each(
+        self,
+        context: &mut dyn SplitContext,
+        emit: &mut dyn FnMut(In) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let mut result = Ok(());
+        (self.split)(context, &mut |item| {
+            if result.is_ok() {
+                result = emit(item);
+            }
+        });
+        result
+    }
+}
+
+pub(crate) struct WorkDirCtx {
     id: u64,
     work_dir: PathBuf,
     args: Vec<String>,
 }
 
-impl SplitContext for WorkDirCtx {
-    fn args(&self) -> &Vec<String> {
-        &self.args
+impl WorkDirCtx {
+    pub(crate) fn new(work_dir: &Path, args: &[String]) -> Self {
+        WorkDirCtx {
+            id: 1000,
+            work_dir: work_dir.into(),
+            args: args.into(),
+        }
     }
 
-    fn new_blob(&mut self) -> Output {
+    fn alloc(&mut self, dir: &Path, ext: &str) -> PathBuf {
         loop {
             let id = self.id;
             self.id += 1000;
-            let name = format!("{:06x}.bin", id);
-            let output_path = self.work_dir.join(name);
+            let name = format!("{:06x}.{}", id, ext);
+            let output_path = dir.join(name);
             if !output_path.exists() {
-                return Output(output_path);
+                return output_path;
             }
         }
     }
 }
 
+impl SplitContext for WorkDirCtx {
+    fn args(&self) -> &Vec<String> {
+        &self.args
+    }
+
+    fn new_blob(&mut self) -> Output {
+        let work_dir = self.work_dir.clone();
+        Output(self.alloc(&work_dir, "bin"))
+    }
+
+    fn new_shared_blob(&mut self) -> Output {
+        let shared_dir = self.work_dir.join(SHARED_DIR);
+        // A failure surfaces when the output is opened.
+        let _ = std::fs::create_dir_all(&shared_dir);
+        Output(self.alloc(&shared_dir, "bin"))
+    }
+
+    fn new_blob_dir(&mut self) -> OutputDir {
+        let work_dir = self.work_dir.clone();
+        OutputDir(self.alloc(&work_dir, "dir"))
+    }
+
+    fn progress(&mut self, fraction: f64, message: &str) {
+        progress::report(&self.work_dir, fraction, message)
+    }
+}
+
+#[cfg(any(test, not(target_arch = "wasm32")))]
 pub(crate) fn split_into<S: Splitter>(
     splitter: S,
     base_path: &Path,
     args: &[String],
 ) -> Result<Vec<TaskDef>, Error> {
-    let mut ctx = WorkDirCtx {
-        id: 1000,
-        work_dir: base_path.into(),
-        args: args.into(),
-    };
-    splitter
-        .split(&mut ctx)
-        .into_iter()
-        .map(|item| IntoTaskDef::into_task_def(item, base_path))
-        .collect()
+    let mut tasks = Vec::new();
+    split_each_into(splitter, base_path, args, &mut |task| {
+        tasks.push(task);
+        Ok(())
+    })?;
+    Ok(tasks)
+}
+
+/// Passes the task of every work item to `emit` as soon as the splitter emits it.
+pub(crate) fn split_each_into<S: Splitter>(
+    splitter: S,
+    base_path: &Path,
+    args: &[String],
+    emit: &mut dyn FnMut(TaskDef) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut ctx = WorkDirCtx::new(base_path, args);
+    let result = splitter.split_each(&mut ctx, &mut |item| {
+        emit(IntoTaskDef::into_task_def(item, base_path)?)
+    });
+    progress::clear(base_path);
+    result
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::blob::Blob;
+    use crate::blob::{Blob, BlobDir};
     use std::io::Write;
 
     fn my_spliter(ctx: &mut dyn SplitContext) -> Vec<(Blob, u32)> {
@@ -106,4 +256,91 @@ mod test {
 
         eprintln!("{}", serde_json::to_string(&tasks).unwrap());
     }
+
+    #[test]
+    fn test_split_shared() {
+        let base = std::env::temp_dir().join("gwasm-dispatcher-test_split_shared");
+        std::fs::create_dir_all(&base).unwrap();
+        let base = base.canonicalize().unwrap();
+
+        let tasks = split_into(
+            |ctx: &mut dyn SplitContext| {
+                let dataset = ctx.new_shared_blob().bytes("dataset").unwrap();
+                (0..3)
+                    .map(|i| {
+                        let own = ctx.new_blob().bytes(format!("part {}", i)).unwrap();
+                        (dataset.clone(), own)
+                    })
+                    .collect::<Vec<_>>()
+            },
+            &base,
+            &[],
+        )
+        .unwrap();
+
+        for task in &tasks {
+            let blobs = task.blob_refs().collect::<Vec<_>>();
+            assert!(blobs[0].shared);
+            assert_eq!(blobs[0].path, tasks[0].blob_refs().next().unwrap().path);
+            assert!(blobs[0].path.starts_with("shared/"));
+            assert!(!blobs[1].shared);
+        }
+    }
+
+    #[test]
+    fn test_split_streaming() {
+        let base = std::env::temp_dir().join("gwasm-dispatcher-test_split_streaming");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+        let base = base.canonicalize().unwrap();
+
+        let splitter = streaming(
+            |ctx: &mut dyn SplitContext, emit: &mut dyn FnMut((Blob, u32))| {
+                for i in 0..3 {
+                    emit((ctx.new_blob().bytes(format!("part {}", i)).unwrap(), i));
+                }
+            },
+        );
+        let mut tasks = Vec::new();
+        split_each_into(splitter, &base, &[], &mut |task| {
+            // Blobs are complete by the time their work item is emitted.
+            task.verify_blobs(&base)?;
+            tasks.push(task);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(tasks.len(), 3);
+        let (_, i) = <(Blob, u32)>::from_task_def(tasks[2].clone(), &base).unwrap();
+        assert_eq!(i, 2);
+    }
+
+    #[test]
+    fn test_split_dir() {
+        let base = std::env::temp_dir().join("gwasm-dispatcher-test_split_dir");
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+        let base = base.canonicalize().unwrap();
+
+        let tasks = split_into(
+            |ctx: &mut dyn SplitContext| {
+                let tiles = ctx.new_blob_dir();
+                tiles.file("a.bin").unwrap().bytes("a").unwrap();
+                tiles.file("row/b.bin").unwrap().bytes("b").unwrap();
+                vec![(tiles.into_blob_dir(),)]
+            },
+            &base,
+            &[],
+        )
+        .unwrap();
+
+        let (tiles,) = <(BlobDir,)>::from_task_def(tasks[0].clone(), &base).unwrap();
+        assert_eq!(tiles.files().unwrap(), vec!["a.bin", "row/b.bin"]);
+        let blob = tasks[0].blob_dirs().next().unwrap();
+        assert_eq!(blob.size, Some(2));
+        tasks[0].verify_blobs(&base).unwrap();
+
+        std::fs::write(base.join(&blob.path).join("row/c.bin"), "").unwrap();
+        assert!(tasks[0].verify_blobs(&base).is_err());
+    }
 }
@@ -1,25 +1,214 @@
-use crate::error::Error;
-use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use crate::dispatcher::PROTOCOL_VERSION;
+use crate::error::{Error, TaskError};
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{self, DeserializeOwned, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 
+/// Blob file or dir path, relative to the task dir, with the size and SHA-256
+/// digest of its content once it is written.
+///
+/// Serialized as the bare path while no digest is known.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "BlobRefRepr", into = "BlobRefRepr")]
+#[doc(hidden)]
+pub struct BlobRef {
+    pub path: String,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    /// Stored once below `shared/` and referenced by many work items.
+    pub shared: bool,
+}
+
+/// Directory below the split dir holding blobs shared by many work items.
+pub(crate) const SHARED_DIR: &str = "shared";
+
+fn is_false(v: &bool) -> bool {
+    !*v
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum BlobRefRepr {
+    Path(String),
+    Full {
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sha256: Option<String>,
+        #[serde(default, skip_serializing_if = "is_false")]
+        shared: bool,
+    },
+}
+
+impl From<BlobRefRepr> for BlobRef {
+    fn from(repr: BlobRefRepr) -> Self {
+        match repr {
+            BlobRefRepr::Path(path) => BlobRef::new(path),
+            BlobRefRepr::Full {
+                path,
+                size,
+                sha256,
+                shared,
+            } => BlobRef {
+                path,
+                size,
+                sha256,
+                shared,
+            },
+        }
+    }
+}
+
+impl From<BlobRef> for BlobRefRepr {
+    fn from(blob: BlobRef) -> Self {
+        match blob {
+            BlobRef {
+                path,
+                size: None,
+                sha256: None,
+                shared: false,
+            } => BlobRefRepr::Path(path),
+            BlobRef {
+                path,
+                size,
+                sha256,
+                shared,
+            } => BlobRefRepr::Full {
+                path,
+                size,
+                sha256,
+                shared,
+            },
+        }
+    }
+}
+
+impl BlobRef {
+    pub fn new(path: impl Into<String>) -> Self {
+        BlobRef {
+            path: path.into(),
+            size: None,
+            sha256: None,
+            shared: false,
+        }
+    }
+
+    /// Reference to `path` recording the size and digest of `file`, which may
+    /// also be a directory.
+    pub fn for_file(path: impl Into<String>, file: &Path) -> io::Result<Self> {
+        Ok(BlobRef::with_digest(path, digest_path(file)?))
+    }
+
+    pub(crate) fn with_digest(path: impl Into<String>, (size, sha256): BlobDigest) -> Self {
+        BlobRef {
+            path: path.into(),
+            size: Some(size),
+            sha256: Some(sha256),
+            shared: false,
+        }
+    }
+
+    /// Checks the file at `path` below `base` against the recorded size and digest.
+    pub fn verify(&self, base: &Path) -> Result<(), Error> {
+        if self.size.is_none() && self.sha256.is_none() {
+            return Ok(());
+        }
+        let file = base.join(&self.path);
+        let (size, sha256) = digest_path(&file)?;
+        match (self.size, &self.sha256) {
+            (Some(expected), _) if expected != size => Err(Error::BlobMismatch(format!(
+                "{}: expected {} bytes, found {}",
+                file.display(),
+                expected,
+                size
+            ))),
+            (_, Some(expected)) if *expected != sha256 => Err(Error::BlobMismatch(format!(
+                "{}: SHA-256 digest differs",
+                file.display()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Size and hex encoded SHA-256 digest of a blob.
+pub(crate) type BlobDigest = (u64, String);
+
+/// Total size and digest of a file, or of every file below a directory
+/// together with its relative path.
+pub(crate) fn digest_path(path: &Path) -> io::Result<BlobDigest> {
+    let mut hasher = Sha256::new();
+    let size = if path.is_dir() {
+        digest_dir(path, "", &mut hasher)?
+    } else {
+        io::copy(&mut fs::File::open(path)?, &mut hasher)?
+    };
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+fn digest_dir(dir: &Path, prefix: &str, hasher: &mut Sha256) -> io::Result<u64> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let mut size = 0;
+    for entry in entries {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            size += digest_dir(&entry.path(), &format!("{}/", name), hasher)?;
+        } else {
+            hasher.update(name.as_bytes());
+            hasher.update([0]);
+            size += io::copy(&mut fs::File::open(entry.path())?, hasher)?;
+        }
+    }
+    Ok(size)
+}
+
 #[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 #[doc(hidden)]
 pub enum TaskArg {
     Meta(serde_json::Value),
-    Blob(String),
-    Output(String),
+    Blob(BlobRef),
+    Output(BlobRef),
+    /// Directory of blobs, transferred as a whole.
+    BlobDir(BlobRef),
+    /// Directory for output files, transferred as a whole.
+    OutputDir(BlobRef),
+    /// Failure record left by an executor in place of its output.
+    Error(TaskError),
+    /// Work items spawned by an executor, after its output.
+    Spawn(Vec<TaskDef>),
+    /// Indexes of the subtasks running spawned work items, as passed to merge.
+    Spawned(Vec<usize>),
 }
 
 impl TaskArg {
+    /// Kind of a work item or result arg, `None` for failure and spawn records.
+    pub fn kind(&self) -> Option<ArgKind> {
+        match self {
+            TaskArg::Meta(_) => Some(ArgKind::Meta),
+            TaskArg::Blob(_) => Some(ArgKind::Blob),
+            TaskArg::Output(_) => Some(ArgKind::Output),
+            TaskArg::BlobDir(_) => Some(ArgKind::BlobDir),
+            TaskArg::OutputDir(_) => Some(ArgKind::OutputDir),
+            TaskArg::Error(_) | TaskArg::Spawn(_) | TaskArg::Spawned(_) => None,
+        }
+    }
+
     fn rebase_to(&mut self, to_path: &str) -> Result<(), Error> {
         match self {
-            TaskArg::Output(ref mut path) => {
-                *path = format!("{}/{}", to_path, path);
-            }
-            TaskArg::Blob(ref mut path) => {
-                *path = format!("{}/{}", to_path, path);
+            TaskArg::Output(ref mut blob)
+            | TaskArg::Blob(ref mut blob)
+            | TaskArg::OutputDir(ref mut blob)
+            | TaskArg::BlobDir(ref mut blob) => {
+                blob.path = format!("{}/{}", to_path, blob.path);
             }
             _ => (),
         }
@@ -28,14 +217,195 @@ impl TaskArg {
 }
 
 #[doc(hidden)]
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct TaskDef(pub Vec<TaskArg>);
 
+/// Contents of a task file: `tasks.json`, `task.json` or the merge inputs.
+///
+/// Files of protocol 2 start with a header, `{"version": 2, "data": ..}`.
+/// Protocol 1 files hold the bare contents; they are still read, and written
+/// for apps that predate the header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskFile<T> {
+    pub version: u32,
+    pub data: T,
+}
+
+#[derive(Serialize)]
+struct TaskFileRef<'a, T> {
+    version: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TaskFileHeader<T> {
+    version: u32,
+    data: T,
+}
+
+impl<T> TaskFile<T> {
+    /// Contents in the format of this dispatcher.
+    pub fn new(data: T) -> Self {
+        TaskFile {
+            version: PROTOCOL_VERSION,
+            data,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for TaskFile<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.version < 2 {
+            return self.data.serialize(serializer);
+        }
+        TaskFileRef {
+            version: self.version,
+            data: &self.data,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for TaskFile<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TaskFileVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for TaskFileVisitor<T> {
+            type Value = TaskFile<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a task file with a version header or a bare list")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<TaskFile<T>, A::Error> {
+                Ok(TaskFile {
+                    version: 1,
+                    data: T::deserialize(SeqAccessDeserializer::new(seq))?,
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<TaskFile<T>, A::Error> {
+                let file = TaskFileHeader::deserialize(MapAccessDeserializer::new(map))?;
+                if file.version > PROTOCOL_VERSION {
+                    return Err(de::Error::custom(Error::UnsupportedVersion(file.version)));
+                }
+                Ok(TaskFile {
+                    version: file.version,
+                    data: file.data,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(TaskFileVisitor(PhantomData))
+    }
+}
+
 impl TaskDef {
+    /// Checks arity and kinds of the args against those expected by the decoder.
+    ///
+    /// Failure and spawn records are not counted.
+    pub fn check_args(&self, kinds: &[ArgKind]) -> Result<(), Error> {
+        let found = self.0.iter().filter_map(TaskArg::kind).collect::<Vec<_>>();
+        if found.len() != kinds.len() {
+            return Err(Error::ArgCount {
+                expected: kinds.len(),
+                found: found.len(),
+            });
+        }
+        match kinds
+            .iter()
+            .zip(found)
+            .enumerate()
+            .find(|(_, (e, f))| *e != f)
+        {
+            Some((index, (&expected, found))) => Err(Error::ArgKindMismatch {
+                index,
+                expected,
+                found,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Output of a failed executor.
+    pub fn failed(error: TaskError) -> Self {
+        TaskDef(vec![TaskArg::Error(error)])
+    }
+
+    /// Returns the executor failure, if this is a failed task output.
+    pub fn failure(&self) -> Option<&TaskError> {
+        match self.0.first() {
+            Some(TaskArg::Error(e)) => Some(e),
+            _ => None,
+        }
+    }
+
+    /// Work items spawned by the executor that produced this output.
+    pub fn spawned(&self) -> &[TaskDef] {
+        match self.0.last() {
+            Some(TaskArg::Spawn(work)) => work,
+            _ => &[],
+        }
+    }
+
+    /// Replaces spawned work items with the indexes of the subtasks running them.
+    pub fn link_spawned(mut self, children: Vec<usize>) -> Self {
+        if let Some(TaskArg::Spawn(_)) = self.0.last() {
+            self.0.pop();
+        }
+        if !children.is_empty() {
+            self.0.push(TaskArg::Spawned(children));
+        }
+        self
+    }
+
+    pub(crate) fn take_spawned(&mut self) -> Vec<usize> {
+        match self.0.pop() {
+            Some(TaskArg::Spawned(children)) => children,
+            Some(arg) => {
+                self.0.push(arg);
+                Vec::new()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Checks every blob and output that records a digest, including those of
+    /// spawned work items, against the files below `base`.
+    pub fn verify_blobs(&self, base: &Path) -> Result<(), Error> {
+        for arg in &self.0 {
+            match arg {
+                TaskArg::Blob(blob)
+                | TaskArg::Output(blob)
+                | TaskArg::BlobDir(blob)
+                | TaskArg::OutputDir(blob) => blob.verify(base)?,
+                TaskArg::Spawn(work) => {
+                    for task in work {
+                        task.verify_blobs(base)?;
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn blob_refs(&self) -> impl Iterator<Item = &BlobRef> {
+        self.0.iter().filter_map(move |b| {
+            if let TaskArg::Blob(blob) = b {
+                Some(blob)
+            } else {
+                None
+            }
+        })
+    }
+
     pub fn blobs(&self) -> impl IntoIterator<Item = &str> {
         self.0.iter().filter_map(move |b| {
-            if let TaskArg::Blob(path) = b {
-                Some(path.as_ref())
+            if let TaskArg::Blob(blob) = b {
+                Some(blob.path.as_ref())
             } else {
                 None
             }
@@ -44,8 +414,28 @@ impl TaskDef {
 
     pub fn outputs(&self) -> impl IntoIterator<Item = &str> {
         self.0.iter().filter_map(move |b| {
-            if let TaskArg::Output(path) = b {
-                Some(path.as_ref())
+            if let TaskArg::Output(blob) = b {
+                Some(blob.path.as_ref())
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn blob_dirs(&self) -> impl Iterator<Item = &BlobRef> {
+        self.0.iter().filter_map(move |b| {
+            if let TaskArg::BlobDir(blob) = b {
+                Some(blob)
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn output_dirs(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().filter_map(move |b| {
+            if let TaskArg::OutputDir(blob) = b {
+                Some(blob.path.as_ref())
             } else {
                 None
             }
@@ -54,10 +444,11 @@ impl TaskDef {
 
     pub fn rebase_output(mut self, from_base: &str, to_base: &str) -> Self {
         for arg in &mut self.0 {
-            if let TaskArg::Output(ref mut output_path) = arg {
-                let blob_rel_path = output_path.strip_prefix(from_base).unwrap_or(output_path);
+            if let TaskArg::Output(ref mut output) | TaskArg::OutputDir(ref mut output) = arg {
+                let blob_rel_path = output.path.strip_prefix(from_base).unwrap_or(&output.path);
                 let new_output = format!("{}{}", to_base, blob_rel_path);
-                *output_path = new_output
+                // The file at the new location is yet to be written.
+                *output = BlobRef::new(new_output)
             }
         }
         self
@@ -88,8 +479,36 @@ where
     }
 }
 
+/// Kind of a task arg, as described by the `info` command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArgKind {
+    Meta,
+    Blob,
+    Output,
+    BlobDir,
+    OutputDir,
+}
+
+impl fmt::Display for ArgKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ArgKind::Meta => "meta",
+            ArgKind::Blob => "blob",
+            ArgKind::Output => "output",
+            ArgKind::BlobDir => "blob dir",
+            ArgKind::OutputDir => "output dir",
+        })
+    }
+}
+
 pub trait FromTaskArg: Sized {
     fn from_arg(arg: TaskArg, base: &Path) -> Result<Self, Error>;
+
+    /// Kind of arg accepted by `from_arg`, checked before decoding a task.
+    fn arg_kind() -> ArgKind {
+        ArgKind::Meta
+    }
 }
 
 impl<T: DeserializeOwned + Sized> FromTaskArg for T {
@@ -107,6 +526,36 @@ pub trait IntoTaskDef {
 
 pub trait FromTaskDef: Sized {
     fn from_task_def(task: TaskDef, base: &Path) -> Result<Self, Error>;
+
+    /// Kinds of the task args, in order, or `None` if the type does not describe them.
+    fn arg_kinds() -> Option<Vec<ArgKind>> {
+        None
+    }
+}
+
+/// Task output as received by a merger, either `Out` itself or `Result<Out, TaskError>`.
+///
+/// Plain `Out` fails the merge when any executor reported an error.
+pub trait FromTaskOutput<Out: FromTaskDef>: Sized {
+    fn from_task_output(task: TaskDef, base: &Path) -> Result<Self, Error>;
+}
+
+impl<Out: FromTaskDef> FromTaskOutput<Out> for Out {
+    fn from_task_output(task: TaskDef, base: &Path) -> Result<Self, Error> {
+        match task.failure() {
+            Some(e) => Err(Error::TaskFailed(e.clone())),
+            None => Out::from_task_def(task, base),
+        }
+    }
+}
+
+impl<Out: FromTaskDef> FromTaskOutput<Out> for Result<Out, TaskError> {
+    fn from_task_output(task: TaskDef, base: &Path) -> Result<Self, Error> {
+        match task.failure() {
+            Some(e) => Ok(Err(e.clone())),
+            None => Ok(Ok(Out::from_task_def(task, base)?)),
+        }
+    }
 }
 
 macro_rules! gen_bind {
@@ -120,10 +569,16 @@ macro_rules! gen_bind {
 
         impl<$($t : FromTaskArg),+> FromTaskDef for ($($t,)+) {
             fn from_task_def(task : TaskDef, base : &Path) -> Result<Self, Error> {
+                task.check_args(&[$($t::arg_kind()),+])?;
+                // Arity checked above, spawn records only follow the args.
                 let mut task_iter = task.0.into_iter();
 
                 Ok(($($t::from_arg(task_iter.next().unwrap(), base)?,)+))
             }
+
+            fn arg_kinds() -> Option<Vec<ArgKind>> {
+                Some(vec![$($t::arg_kind()),+])
+            }
         }
 
     }
@@ -163,8 +618,65 @@ fn calc_rebase(from_path: &Path, to_path: &Path) -> PathBuf {
 
 #[cfg(test)]
 mod test {
-    use crate::taskdef::calc_rebase;
-    use std::path::PathBuf;
+    use crate::blob::Blob;
+    use crate::error::Error;
+    use crate::taskdef::{calc_rebase, ArgKind, BlobRef, FromTaskDef, TaskArg, TaskDef, TaskFile};
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_task_file() {
+        let tasks = vec![TaskDef(vec![
+            TaskArg::Meta(serde_json::json!(1)),
+            TaskArg::Blob(BlobRef::new("a.bin")),
+        ])];
+        let json = serde_json::to_value(TaskFile::new(&tasks)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"version": 2, "data": [[{"meta": 1}, {"blob": "a.bin"}]]})
+        );
+        assert_eq!(
+            serde_json::from_value::<TaskFile<Vec<TaskDef>>>(json).unwrap(),
+            TaskFile::new(tasks.clone())
+        );
+
+        let legacy = TaskFile {
+            version: 1,
+            data: &tasks,
+        };
+        let json = serde_json::to_string(&legacy).unwrap();
+        assert_eq!(json, r#"[[{"meta":1},{"blob":"a.bin"}]]"#);
+        let legacy: TaskFile<Vec<TaskDef>> = serde_json::from_str(&json).unwrap();
+        assert_eq!((legacy.version, legacy.data), (1, tasks));
+        assert!(
+            serde_json::from_str::<TaskFile<Vec<TaskDef>>>(r#"{"version": 99, "data": []}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_check_args() {
+        let base = Path::new("");
+        let task: TaskDef = serde_json::from_str(r#"[{"meta": 1}]"#).unwrap();
+        match <(u32, u32)>::from_task_def(task.clone(), base) {
+            Err(Error::ArgCount {
+                expected: 2,
+                found: 1,
+            }) => (),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        match <(Blob,)>::from_task_def(task.clone(), base) {
+            Err(Error::ArgKindMismatch {
+                index: 0,
+                expected: ArgKind::Blob,
+                found: ArgKind::Meta,
+            }) => (),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+
+        let mut spawned = task;
+        spawned.0.push(TaskArg::Spawned(vec![1]));
+        assert_eq!(<(u32,)>::from_task_def(spawned, base).unwrap(), (1,));
+    }
 
     #[test]
     fn test_find_base() {
@@ -185,4 +697,29 @@ mod test {
             PathBuf::from("../../..")
         );
     }
+
+    #[test]
+    fn test_verify_blobs() {
+        let dir = std::env::temp_dir().join("gwasm-dispatcher-test_verify_blobs");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.bin"), b"abc").unwrap();
+
+        let blob = BlobRef::for_file("a.bin", &dir.join("a.bin")).unwrap();
+        let task = TaskDef(vec![
+            TaskArg::Blob(blob),
+            TaskArg::Output(BlobRef::new("b.bin")),
+        ]);
+        let json = serde_json::to_string(&task).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"blob":{"path":"a.bin","size":3,"sha256":"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"}},{"output":"b.bin"}]"#
+        );
+        let task: TaskDef = serde_json::from_str(&json).unwrap();
+        task.verify_blobs(&dir).unwrap();
+
+        std::fs::write(dir.join("a.bin"), b"ab").unwrap();
+        assert!(task.verify_blobs(&dir).is_err());
+        std::fs::write(dir.join("a.bin"), b"abd").unwrap();
+        assert!(task.verify_blobs(&dir).is_err());
+    }
 }
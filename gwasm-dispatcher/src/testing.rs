@@ -0,0 +1,250 @@
+//! Runs dispatcher apps natively, in-process, for `cargo test`.
+//!
+//! Work items, outputs and blobs take the same way as on the local backend:
+//! every value goes through the JSON task files, and every blob is moved into
+//! the dir of the subtask that uses it. The module is left out of wasm builds,
+//! so it adds nothing to the app binary.
+//!
+//! ```edition2018
+//! use gwasm_dispatcher::{testing, MergeContext, SplitContext, WithContext};
+//!
+//! let run = testing::run(
+//!     &["10".to_owned()],
+//!     |ctx: &mut dyn SplitContext| {
+//!         let n: u64 = ctx.args()[0].parse().unwrap();
+//!         (1..=n).map(|v| (v,)).collect::<Vec<_>>()
+//!     },
+//!     |v: u64| (v * v,),
+//!     WithContext(|ctx: &mut dyn MergeContext, results: Vec<(_, (u64,))>| {
+//!         let sum: u64 = results.iter().map(|(_, (square,))| square).sum();
+//!         let output = ctx.result_file("sum.txt").unwrap();
+//!         output.bytes(sum.to_string()).unwrap();
+//!     }),
+//! )
+//! .unwrap();
+//! let result = std::fs::read_to_string(run.result_file("sum.txt")).unwrap();
+//! assert_eq!(result, "385");
+//! ```
+use crate::error::DynError;
+use crate::executor::{exec_for, Executor};
+use crate::merger::{merge_for, MergeOutcome, Merger};
+use crate::splitter::{split_into, Splitter};
+use crate::taskdef::{FromTaskDef, FromTaskOutput, IntoTaskDef, TaskDef};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Work dir of a finished test run, removed when dropped.
+#[derive(Debug)]
+pub struct TestRun {
+    work_dir: PathBuf,
+}
+
+impl TestRun {
+    pub fn work_dir(&self) -> &Path {
+        &self.work_dir
+    }
+
+    /// Location of a file declared by the merger with `MergeContext::result_file`.
+    pub fn result_file(&self, name: &str) -> PathBuf {
+        self.work_dir.join("merge").join("results").join(name)
+    }
+}
+
+impl Drop for TestRun {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.work_dir);
+    }
+}
+
+/// Runs a single round of the app in a fresh temp dir.
+pub fn run<
+    S: Splitter<WorkItem = In>,
+    E: Executor<S::WorkItem, Out>,
+    M: Merger<In, Out, R>,
+    Out: IntoTaskDef + FromTaskDef,
+    R: FromTaskOutput<Out>,
+    In: IntoTaskDef + FromTaskDef,
+>(
+    args: &[String],
+    splitter: S,
+    executor: E,
+    merger: M,
+) -> Result<TestRun, DynError> {
+    static RUN_ID: AtomicUsize = AtomicUsize::new(0);
+
+    let work_dir = env::temp_dir().join(format!(
+        "gwasm-dispatcher-testing-{}-{}",
+        std::process::id(),
+        RUN_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&work_dir);
+    let test_run = TestRun { work_dir };
+    run_in(&test_run.work_dir, args, splitter, executor, merger)?;
+    Ok(test_run)
+}
+
+/// Runs a single round of the app in `work_dir`, which is left in place.
+///
+/// The dir gets the layout of a local backend run: `split`, a `tsk-NNNNNN`
+/// dir per subtask, and `merge`. Work items returned by the merger for the
+/// next round are left in `merge/next`.
+pub fn run_in<
+    S: Splitter<WorkItem = In>,
+    E: Executor<S::WorkItem, Out>,
+    M: Merger<In, Out, R>,
+    Out: IntoTaskDef + FromTaskDef,
+    R: FromTaskOutput<Out>,
+    In: IntoTaskDef + FromTaskDef,
+>(
+    work_dir: &Path,
+    args: &[String],
+    splitter: S,
+    executor: E,
+    merger: M,
+) -> Result<(), DynError> {
+    fs::create_dir_all(work_dir)?;
+    // Blob paths are canonicalized before they are made relative to it.
+    let work_dir = work_dir.canonicalize()?;
+    let split_dir = work_dir.join("split");
+    let merge_dir = work_dir.join("merge");
+    fs::create_dir_all(&split_dir)?;
+    fs::create_dir_all(&merge_dir)?;
+
+    let tasks: Vec<TaskDef> = round_trip(&split_into(splitter, &split_dir, args)?)?;
+
+    // Spawned work items are appended, with blobs found in the output dir of their parent.
+    let mut tasks = tasks
+        .into_iter()
+        .map(|task| (task, split_dir.clone()))
+        .collect::<Vec<_>>();
+    let mut inputs = Vec::new();
+    let mut outputs = Vec::new();
+    let mut idx = 0;
+    while idx < tasks.len() {
+        let (task, blob_dir) = tasks[idx].clone();
+        let task_dir = work_dir.join(format!("tsk-{:06}", idx));
+        let task_input_dir = task_dir.join("in");
+        let task_output_dir = task_dir.join("out");
+        fs::create_dir_all(&task_input_dir)?;
+        fs::create_dir_all(&task_output_dir)?;
+
+        for blob in task.blob_refs().chain(task.blob_dirs()) {
+            place_blob(
+                &blob_dir.join(&blob.path),
+                &task_input_dir.join(&blob.path),
+                blob.shared,
+            )?;
+        }
+        let task = task.rebase_output("", "../out/");
+        let output: TaskDef = round_trip(&exec_for(
+            &executor,
+            idx,
+            round_trip(&task)?,
+            &task_input_dir,
+            &task_output_dir,
+        )?)?;
+
+        let children = (tasks.len()..tasks.len() + output.spawned().len()).collect();
+        for spawned in output.spawned() {
+            tasks.push((spawned.clone(), task_output_dir.clone()));
+        }
+        inputs.push(task.rebase_to(&task_input_dir, &merge_dir)?);
+        outputs.push(
+            output
+                .link_spawned(children)
+                .rebase_to(&task_output_dir, &merge_dir)?,
+        );
+        idx += 1;
+    }
+
+    let in_outs = round_trip(&inputs)?
+        .into_iter()
+        .zip(round_trip(&outputs)?)
+        .collect();
+    match merge_for(merger, args, in_outs, &merge_dir, &merge_dir)? {
+        MergeOutcome::Done => (),
+        MergeOutcome::Continue(tasks) => {
+            let next_dir = merge_dir.join("next");
+            fs::create_dir_all(&next_dir)?;
+            fs::write(next_dir.join("tasks.json"), serde_json::to_vec(&tasks)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Passes a value through JSON, as it would through a task file.
+fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> Result<T, DynError> {
+    Ok(serde_json::from_slice(&serde_json::to_vec(value)?)?)
+}
+
+/// Moves a blob into a subtask input dir, or copies it if shared with other subtasks.
+fn place_blob(from: &Path, to: &Path, shared: bool) -> Result<(), DynError> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if !shared {
+        fs::rename(from, to)?;
+    } else if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            place_blob(&entry.path(), &to.join(entry.file_name()), true)?;
+        }
+    } else {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blob::Blob;
+    use crate::merger::{MergeContext, WithContext};
+    use crate::splitter::SplitContext;
+    use std::io::Read;
+
+    #[test]
+    fn test_run() {
+        let run = run(
+            &["4".to_owned()],
+            |ctx: &mut dyn SplitContext| {
+                let n: u32 = ctx.args()[0].parse().unwrap();
+                let offset = ctx.new_shared_blob().bytes("100").unwrap();
+                (0..n)
+                    .map(|i| {
+                        let value = ctx.new_blob().bytes(i.to_string()).unwrap();
+                        (value, offset.clone())
+                    })
+                    .collect::<Vec<_>>()
+            },
+            |value: Blob, offset: Blob| {
+                let read = |blob: &Blob| {
+                    let mut s = String::new();
+                    blob.open().unwrap().read_to_string(&mut s).unwrap();
+                    s.parse::<u32>().unwrap()
+                };
+                (read(&value) + read(&offset),)
+            },
+            WithContext(
+                |ctx: &mut dyn MergeContext, results: Vec<((Blob, Blob), (u32,))>| {
+                    let sum: u32 = results.iter().map(|(_, (v,))| v).sum();
+                    ctx.result_file("sum.txt")
+                        .unwrap()
+                        .bytes(sum.to_string())
+                        .unwrap();
+                },
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(fs::read(run.result_file("sum.txt")).unwrap(), b"406");
+        let work_dir = run.work_dir().to_owned();
+        drop(run);
+        assert!(!work_dir.exists());
+    }
+}
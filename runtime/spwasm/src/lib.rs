@@ -6,7 +6,9 @@ use sp_wasm_engine::sandbox as sp;
 use sp_wasm_engine::sandbox::engine::EngineRef;
 use sp_wasm_engine::sandbox::load::Bytes;
 use std::convert::TryInto;
+use std::fs::File;
 use std::path::Path;
+use std::time::Instant;
 
 type Result<T> = anyhow::Result<T>;
 
@@ -24,16 +26,27 @@ pub fn engine() -> Result<SpEngine> {
 
 pub struct SpSandbox {
     inner: Option<sp::Sandbox>,
+    deadline: Option<Instant>,
 }
 
 impl Engine for SpEngine {
     type Sandbox = SpSandbox;
 
-    fn new() -> Result<Self> {
+    fn new(config: &EngineConfig) -> Result<Self> {
+        if config.fuel_metering {
+            anyhow::bail!("fuel metering is not supported by spwasm runtime");
+        }
         engine()
     }
 
-    fn sandbox(&self, args: Vec<String>) -> Result<Self::Sandbox> {
+    // SpiderMonkey manages guest memory itself, limits can't be applied.
+    fn sandbox(&self, args: Vec<String>, limits: &ResourceLimits) -> Result<Self::Sandbox> {
+        if limits.max_memory.is_some()
+            || limits.max_table_elements.is_some()
+            || limits.max_instances.is_some()
+        {
+            anyhow::bail!("resource limits are not supported by spwasm runtime");
+        }
         let mut inner = sp::Sandbox::new_on_engine(self.inner.clone())
             .map_err(anyhow::Error::msg)
             .context("engine create")?
@@ -44,7 +57,10 @@ impl Engine for SpEngine {
             .init()
             .map_err(anyhow::Error::msg)
             .context("box init")?;
-        Ok(SpSandbox { inner: Some(inner) })
+        Ok(SpSandbox {
+            inner: Some(inner),
+            deadline: None,
+        })
     }
 
     #[cfg(windows)]
@@ -60,6 +76,11 @@ impl Engine for SpEngine {
     fn supports_workdir(&self) -> bool {
         true
     }
+
+    // The emscripten runtime prints straight to the host terminal.
+    fn supports_output_capture(&self) -> bool {
+        false
+    }
 }
 
 #[inline]
@@ -101,16 +122,36 @@ impl Sandbox for SpSandbox {
         Ok(())
     }
 
-    fn run(self, code: Self::Code) -> Result<()> {
-        let _ = self
-            .inner
-            .unwrap()
-            .run(code.js, code.wasm)
-            .map_err(anyhow::Error::msg)
-            .context("failed to execute emscripten code")?;
+    fn capture_output(&mut self, _stdout: File, _stderr: File) -> Result<()> {
+        anyhow::bail!("output capture is not supported by spwasm runtime")
+    }
+
+    // SpiderMonkey execution can't be interrupted from the outside, so the
+    // deadline is only checked once the code returns.
+    fn deadline(&mut self, deadline: Instant) -> Result<()> {
+        self.deadline = Some(deadline);
         Ok(())
     }
 
+    fn run(self, code: Self::Code) -> Result<RunOutcome> {
+        let started = Instant::now();
+        let result = self.inner.unwrap().run(code.js, code.wasm);
+        let stats = RunStats {
+            wall_time: started.elapsed(),
+            ..RunStats::default()
+        };
+        let exit = match (result, self.deadline) {
+            (_, Some(deadline)) if Instant::now() > deadline => Exit::Timeout,
+            (Ok(_), _) => Exit::Success,
+            // Emscripten reports exit codes and aborts alike, as plain messages.
+            (Err(e), _) => Exit::Trap {
+                message: e.to_string(),
+                backtrace: None,
+            },
+        };
+        Ok(RunOutcome { exit, stats })
+    }
+
     fn for_wasm_path(&self, wasm_path: &Path) -> Result<Self::Code> {
         log::debug!("loading wasm: {}", wasm_path.display());
         let js_path = wasm_path.with_extension("js");
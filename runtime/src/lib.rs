@@ -1,24 +1,145 @@
-use std::path::Path;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 type Result<T> = anyhow::Result<T>;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub enum Mode {
     Ro,
     Rw,
     Wo,
 }
 
+/// Error for a guest that did not finish before its deadline.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "execution timed out")
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// How the guest finished.
+#[derive(Debug, Clone)]
+pub enum Exit {
+    /// Returned from `_start` or called `proc_exit(0)`.
+    Success,
+    /// Called `proc_exit` with a non-zero code.
+    Code(i32),
+    Trap {
+        message: String,
+        /// Symbolized wasm backtrace, when the runtime provides one.
+        backtrace: Option<String>,
+    },
+    /// Interrupted by the sandbox deadline.
+    Timeout,
+}
+
+impl Exit {
+    pub fn is_success(&self) -> bool {
+        matches!(self, Exit::Success)
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exit::Success => write!(f, "exited successfully"),
+            Exit::Code(code) => write!(f, "exited with code {}", code),
+            Exit::Trap { message, .. } => write!(f, "trapped: {}", message),
+            Exit::Timeout => Timeout.fmt(f),
+        }
+    }
+}
+
+/// Error for a guest that finished with anything but [`Exit::Success`].
+#[derive(Debug, Clone)]
+pub struct ExitError(pub Exit);
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "guest {}", self.0)?;
+        if let Exit::Trap {
+            backtrace: Some(backtrace),
+            ..
+        } = &self.0
+        {
+            write!(f, "\n{}", backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ExitError {}
+
+/// Engine wide settings.
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    /// Count fuel consumed by the guest. Only supported by wasmtime.
+    pub fuel_metering: bool,
+    /// Directory for compiled modules reused across runs, `None` compiles on every run.
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// Caps on guest resources of a single sandbox. `None` means unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct ResourceLimits {
+    /// Maximum size of a linear memory in bytes.
+    pub max_memory: Option<usize>,
+    /// Maximum number of elements in a table.
+    pub max_table_elements: Option<usize>,
+    /// Maximum number of module instances.
+    pub max_instances: Option<usize>,
+}
+
+/// Resources used by a single [`Sandbox::run`].
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    /// Fuel consumed, when fuel metering is enabled and the guest did not time out.
+    pub fuel: Option<u64>,
+    pub wall_time: Duration,
+    /// Peak size of the guest linear memory in bytes, when the runtime tracks it.
+    pub peak_memory: Option<u64>,
+}
+
+/// Result of a guest run that got as far as executing `_start`.
+#[derive(Debug, Clone)]
+pub struct RunOutcome {
+    pub exit: Exit,
+    pub stats: RunStats,
+}
+
+impl RunOutcome {
+    /// Converts anything but a clean exit into an error, [`Timeout`] for timeouts
+    /// and [`ExitError`] otherwise.
+    pub fn into_result(self) -> Result<RunStats> {
+        match self.exit {
+            Exit::Success => Ok(self.stats),
+            Exit::Timeout => Err(Timeout.into()),
+            exit => Err(ExitError(exit).into()),
+        }
+    }
+}
+
 pub trait Engine: Clone {
     type Sandbox: Sandbox;
 
-    fn new() -> Result<Self>;
+    fn new(config: &EngineConfig) -> Result<Self>;
 
-    fn sandbox(&self, args: Vec<String>) -> Result<Self::Sandbox>;
+    fn sandbox(&self, args: Vec<String>, limits: &ResourceLimits) -> Result<Self::Sandbox>;
 
     fn supports_overlay_mount(&self) -> bool;
 
     fn supports_workdir(&self) -> bool;
+
+    /// Whether [`Sandbox::capture_output`] works, other sandboxes print guest
+    /// output to the host terminal.
+    fn supports_output_capture(&self) -> bool;
 }
 
 pub trait Sandbox {
@@ -28,7 +149,15 @@ pub trait Sandbox {
 
     fn work_dir(&mut self, dir: &str) -> Result<()>;
 
-    fn run(self, code: Self::Code) -> Result<()>;
+    /// Redirects guest stdout and stderr into the given files.
+    fn capture_output(&mut self, stdout: File, stderr: File) -> Result<()>;
+
+    /// Interrupts execution once `deadline` passes; `run` then reports [`Exit::Timeout`].
+    fn deadline(&mut self, deadline: Instant) -> Result<()>;
+
+    /// Runs the guest. Errors are reserved for failures of the host side, how
+    /// the guest itself finished is reported in [`RunOutcome::exit`].
+    fn run(self, code: Self::Code) -> Result<RunOutcome>;
 
     fn for_wasm_path(&self, path: &Path) -> Result<Self::Code>;
 }
@@ -0,0 +1,259 @@
+use ::wasmtime as w;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+type Result<T> = anyhow::Result<T>;
+
+/// Compiled modules kept in the cache dir, least recently used ones are pruned.
+const MAX_ARTIFACTS: usize = 32;
+const ARTIFACT_EXTENSION: &str = "cwasm";
+/// SHA-256 of an artifact, stored next to it.
+const DIGEST_EXTENSION: &str = "sha256";
+
+/// Module of a single key, empty until compiled.
+type ModuleSlot = Arc<Mutex<Option<w::Module>>>;
+
+/// Compiled modules shared by all sandboxes of an engine.
+///
+/// Modules are keyed by the wasm hash and the engine settings. They are kept
+/// in memory for the lifetime of the engine and, when a cache dir is set,
+/// stored on disk, so later runs skip compilation.
+#[derive(Clone)]
+pub struct ModuleCache {
+    modules: Arc<Mutex<HashMap<String, ModuleSlot>>>,
+    dir: Option<PathBuf>,
+}
+
+impl ModuleCache {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        ModuleCache {
+            modules: Default::default(),
+            dir,
+        }
+    }
+
+    pub fn load(&self, engine: &w::Engine, path: &Path) -> Result<w::Module> {
+        let wasm = fs::read(path)?;
+        let key = module_key(engine, &wasm);
+        let slot = self
+            .modules
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        // Held while compiling, so concurrent sandboxes wait for a single
+        // compilation of the same module only.
+        let mut slot = slot.lock().unwrap();
+        if let Some(module) = &*slot {
+            return Ok(module.clone());
+        }
+
+        let module = match &self.dir {
+            Some(dir) => load_cached(engine, dir, &key, &wasm)?,
+            None => w::Module::new(engine, &wasm)?,
+        };
+        *slot = Some(module.clone());
+        Ok(module)
+    }
+}
+
+fn module_key(engine: &w::Engine, wasm: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    format!("{:x}-{:016x}", Sha256::digest(wasm), hasher.finish())
+}
+
+fn load_cached(engine: &w::Engine, dir: &Path, key: &str, wasm: &[u8]) -> Result<w::Module> {
+    let artifact_path = dir.join(key).with_extension(ARTIFACT_EXTENSION);
+
+    if artifact_path.exists() {
+        // Safety: the bytes match the digest written by `store` next to the
+        // output of `Module::serialize` of an engine with the same
+        // compatibility hash, so changed or truncated artifacts are compiled
+        // again instead.
+        match read_artifact(&artifact_path)
+            .and_then(|artifact| unsafe { w::Module::deserialize(engine, artifact) })
+        {
+            Ok(module) => {
+                log::debug!("using compiled module {}", artifact_path.display());
+                // Marks the artifact as recently used for pruning.
+                let _ = fs::File::options()
+                    .write(true)
+                    .open(&artifact_path)
+                    .and_then(|f| f.set_modified(SystemTime::now()));
+                return Ok(module);
+            }
+            Err(e) => log::warn!(
+                "ignoring broken compiled module {}: {}",
+                artifact_path.display(),
+                e
+            ),
+        }
+    }
+
+    let module = w::Module::new(engine, wasm)?;
+    if let Err(e) = store(&module, dir, &artifact_path) {
+        log::warn!(
+            "failed to store compiled module {}: {}",
+            artifact_path.display(),
+            e
+        );
+    }
+    if let Err(e) = prune(dir) {
+        log::warn!("failed to prune compiled modules: {}", e);
+    }
+    Ok(module)
+}
+
+/// Reads an artifact, checking it against its stored digest.
+fn read_artifact(artifact_path: &Path) -> Result<Vec<u8>> {
+    let artifact = fs::read(artifact_path)?;
+    let digest = fs::read_to_string(artifact_path.with_extension(DIGEST_EXTENSION))?;
+    if format!("{:x}", Sha256::digest(&artifact)) != digest {
+        anyhow::bail!("digest mismatch");
+    }
+    Ok(artifact)
+}
+
+fn store(module: &w::Module, dir: &Path, artifact_path: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let artifact = module.serialize()?;
+    write_file(artifact_path, &artifact)?;
+    // Written last, an artifact without its digest is compiled again.
+    write_file(
+        &artifact_path.with_extension(DIGEST_EXTENSION),
+        format!("{:x}", Sha256::digest(&artifact)).as_bytes(),
+    )
+}
+
+/// Writes aside and renames, so other runs never see a partial file.
+fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+    let result = fs::write(&tmp_path, contents).and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    Ok(result?)
+}
+
+/// Removes all but the [`MAX_ARTIFACTS`] most recently used artifacts, which
+/// also drops the ones of other engine settings over time.
+fn prune(dir: &Path) -> Result<()> {
+    let mut artifacts = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext == ARTIFACT_EXTENSION)
+        {
+            artifacts.push((fs::metadata(&path)?.modified()?, path));
+        }
+    }
+    if artifacts.len() <= MAX_ARTIFACTS {
+        return Ok(());
+    }
+    artifacts.sort_unstable_by_key(|(modified, _)| Reverse(*modified));
+    for (_, path) in artifacts.drain(MAX_ARTIFACTS..) {
+        log::debug!("removing compiled module {}", path.display());
+        let _ = fs::remove_file(path.with_extension(DIGEST_EXTENSION));
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gwr-runtime-wasmtime-test_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn artifacts(dir: &Path) -> usize {
+        fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension()
+                    .is_some_and(|ext| ext == ARTIFACT_EXTENSION)
+            })
+            .count()
+    }
+
+    fn exports(module: &w::Module) -> Vec<String> {
+        module.exports().map(|e| e.name().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_module_cache() {
+        let dir = test_dir("module_cache");
+        let cache_dir = dir.join("cache");
+        let engine = w::Engine::default();
+        let wasm_path = dir.join("main.wat");
+
+        fs::write(&wasm_path, r#"(module (func (export "a")))"#).unwrap();
+        let cache = ModuleCache::new(Some(cache_dir.clone()));
+        let module = cache.load(&engine, &wasm_path).unwrap();
+        assert_eq!(exports(&module), ["a"]);
+        assert_eq!(artifacts(&cache_dir), 1);
+
+        // A changed file is a miss, even at the same path.
+        fs::write(&wasm_path, r#"(module (func (export "b")))"#).unwrap();
+        let module = cache.load(&engine, &wasm_path).unwrap();
+        assert_eq!(exports(&module), ["b"]);
+        assert_eq!(artifacts(&cache_dir), 2);
+
+        // Artifacts on disk are only trusted with a matching digest, so the
+        // artifact of "a" passed off as the one of "b" is compiled again.
+        let artifact = |wat: &str| {
+            let key = module_key(&engine, wat.as_bytes());
+            cache_dir.join(key).with_extension(ARTIFACT_EXTENSION)
+        };
+        let artifact_b = artifact(r#"(module (func (export "b")))"#);
+        fs::copy(artifact(r#"(module (func (export "a")))"#), &artifact_b).unwrap();
+        let cache = ModuleCache::new(Some(cache_dir.clone()));
+        let module = cache.load(&engine, &wasm_path).unwrap();
+        assert_eq!(exports(&module), ["b"]);
+        // And stored again, with the digest matching.
+        assert!(read_artifact(&artifact_b).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = test_dir("prune");
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        for i in 0..MAX_ARTIFACTS + 2 {
+            let path = dir.join(format!("{}.{}", i, ARTIFACT_EXTENSION));
+            fs::write(&path, "").unwrap();
+            let modified = old + Duration::from_secs(i as u64);
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        }
+        fs::write(dir.join("other"), "").unwrap();
+
+        prune(&dir).unwrap();
+        assert!(!dir.join(format!("0.{}", ARTIFACT_EXTENSION)).exists());
+        assert!(!dir.join(format!("1.{}", ARTIFACT_EXTENSION)).exists());
+        assert!(dir.join(format!("2.{}", ARTIFACT_EXTENSION)).exists());
+        assert!(dir.join("other").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), MAX_ARTIFACTS + 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
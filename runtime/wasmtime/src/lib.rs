@@ -1,42 +1,75 @@
 use gwr_runtime_api::*;
 
+mod cache;
+
 use ::wasmtime as w;
-use std::collections::{HashMap, HashSet};
+use cache::ModuleCache;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, OutputFile, WasiCtxBuilder};
 
 type Result<T> = anyhow::Result<T>;
 
-pub fn engine() -> Result<WtEngine> {
+/// Resolution of sandbox deadlines.
+const EPOCH_TICK: Duration = Duration::from_millis(100);
+
+pub fn engine(engine_config: &EngineConfig) -> Result<WtEngine> {
     let mut config = w::Config::default();
     config
         .cranelift_opt_level(w::OptLevel::Speed)
-        .debug_info(false);
-    let engine = w::Engine::new(&config);
-    Ok(WtEngine { engine })
+        .debug_info(false)
+        .wasm_backtrace_details(w::WasmBacktraceDetails::Enable)
+        .epoch_interruption(true)
+        .consume_fuel(engine_config.fuel_metering);
+    let engine = w::Engine::new(&config)?;
+
+    let ticker = engine.weak();
+    std::thread::Builder::new()
+        .name("wasmtime-epoch".into())
+        .spawn(move || {
+            while let Some(engine) = ticker.upgrade() {
+                engine.increment_epoch();
+                drop(engine);
+                std::thread::sleep(EPOCH_TICK);
+            }
+        })?;
+    Ok(WtEngine {
+        engine,
+        fuel_metering: engine_config.fuel_metering,
+        modules: ModuleCache::new(engine_config.cache_dir.clone()),
+    })
 }
 
 #[derive(Clone)]
 pub struct WtEngine {
     engine: w::Engine,
+    fuel_metering: bool,
+    modules: ModuleCache,
 }
 
 impl Engine for WtEngine {
     type Sandbox = WtBox;
 
-    fn new() -> Result<Self> {
-        engine()
+    fn new(config: &EngineConfig) -> Result<Self> {
+        engine(config)
     }
 
-    fn sandbox(&self, args: Vec<String>) -> Result<Self::Sandbox> {
-        let store = w::Store::new(&self.engine);
+    fn sandbox(&self, args: Vec<String>, limits: &ResourceLimits) -> Result<Self::Sandbox> {
         let mounts = Default::default();
         let mut my_args = vec!["self".to_owned()];
         my_args.extend(args);
         Ok(WtBox {
-            store,
+            engine: self.engine.clone(),
+            fuel_metering: self.fuel_metering,
+            modules: self.modules.clone(),
             args: my_args,
             mounts,
+            work_dir: None,
+            output: None,
+            deadline: None,
+            limits: limits.clone(),
         })
     }
 
@@ -47,106 +80,493 @@ impl Engine for WtEngine {
 
     #[inline]
     fn supports_workdir(&self) -> bool {
-        false
+        true
+    }
+
+    #[inline]
+    fn supports_output_capture(&self) -> bool {
+        true
     }
 }
 
 pub struct WtBox {
-    store: w::Store,
+    engine: w::Engine,
+    fuel_metering: bool,
+    modules: ModuleCache,
     args: Vec<String>,
-    mounts: Vec<(String, File)>,
+    mounts: Vec<(String, PathBuf, Mode)>,
+    work_dir: Option<String>,
+    output: Option<(File, File)>,
+    deadline: Option<Instant>,
+    limits: ResourceLimits,
 }
 
 impl Sandbox for WtBox {
     type Code = WtCode;
 
-    fn mount<PathRef: AsRef<Path>>(&mut self, src: PathRef, des: &str, _mode: Mode) -> Result<()> {
-        self.mounts.push((
-            des.to_owned(),
-            std::fs::OpenOptions::new().read(true).open(src)?,
-        ));
+    fn mount<PathRef: AsRef<Path>>(&mut self, src: PathRef, des: &str, mode: Mode) -> Result<()> {
+        self.mounts
+            .push((des.to_owned(), src.as_ref().to_owned(), mode));
+        Ok(())
+    }
+
+    fn work_dir(&mut self, dir: &str) -> Result<()> {
+        if !dir.starts_with('/') {
+            anyhow::bail!("work dir must be an absolute path: {}", dir);
+        }
+        self.work_dir = Some(dir.to_owned());
+        Ok(())
+    }
+
+    fn capture_output(&mut self, stdout: File, stderr: File) -> Result<()> {
+        self.output = Some((stdout, stderr));
         Ok(())
     }
 
-    fn work_dir(&mut self, _dir: &str) -> Result<()> {
-        Err(anyhow::anyhow!("unimplemented work dir"))
+    fn deadline(&mut self, deadline: Instant) -> Result<()> {
+        self.deadline = Some(deadline);
+        Ok(())
     }
 
-    fn run(self, code: Self::Code) -> Result<()> {
+    fn run(self, code: Self::Code) -> Result<RunOutcome> {
         log::debug!(
-            "starting: args=[{:?}]; mounts=[{:?}] ",
+            "starting: args=[{:?}]; mounts=[{:?}]; work_dir={:?}",
             self.args,
-            self.mounts
+            self.mounts,
+            self.work_dir
         );
         let module = code.module;
 
-        let mut modules = module
-            .imports()
-            .iter()
-            .map(|e| e.module())
-            .collect::<HashSet<_>>();
-
-        let mut deps = HashMap::new();
-        if modules.remove("wasi_unstable") {
-            let snapshot0 = wasmtime_wasi::old::snapshot_0::create_wasi_instance(
-                &self.store,
-                self.mounts.as_ref(),
-                self.args.as_ref(),
-                &[("RUST_BACKTRACE".to_string(), "full".to_string())],
-            )?;
-            deps.insert("wasi_unstable", snapshot0);
-        }
+        let mut linker: w::Linker<State> = w::Linker::new(&self.engine);
+        preview1::add_to_linker_sync(&mut linker, |state| &mut state.wasi)?;
+        wasmtime_wasi::preview0::add_to_linker_sync(&mut linker, |state| &mut state.wasi)?;
 
-        if modules.remove("wasi_snapshot_preview1") {
-            let runtime = wasmtime_wasi::create_wasi_instance(
-                &self.store,
-                self.mounts.as_ref(),
-                self.args.as_ref(),
-                &[("RUST_BACKTRACE".to_string(), "full".to_string())],
-            )?;
-            deps.insert("wasi_snapshot_preview1", runtime);
+        let mut wasi = WasiCtxBuilder::new();
+        wasi.args(&self.args).env("RUST_BACKTRACE", "full");
+        for (des, src, mode) in &self.mounts {
+            let (dir_perms, file_perms) = into_perms(*mode);
+            wasi.preopened_dir(src, des, dir_perms, file_perms)?;
         }
-
-        if !modules.is_empty() {
-            anyhow::bail!("missing modules {:?}", modules);
+        // WASI has no current directory; guest libc resolves relative paths
+        // against the "." preopen.
+        if let Some(dir) = &self.work_dir {
+            let (src, mode) = self.resolve(dir)?;
+            let (dir_perms, file_perms) = into_perms(mode);
+            wasi.preopened_dir(src, ".", dir_perms, file_perms)?
+                .env("PWD", dir);
         }
+        match self.output {
+            Some((stdout, stderr)) => wasi
+                .inherit_stdin()
+                .stdout(OutputFile::new(stdout))
+                .stderr(OutputFile::new(stderr)),
+            None => wasi.inherit_stdio(),
+        };
 
-        let externs = module
-            .imports()
-            .iter()
-            .map(|item| {
-                deps.get(item.module())
-                    .unwrap()
-                    .find_export_by_name(item.name())
-                    .cloned()
-                    .ok_or_else(|| anyhow::anyhow!("symbol not found: {:?}", item))
-            })
-            .collect::<Result<Vec<_>>>()?;
+        let state = State {
+            wasi: wasi.build_p1(),
+            limits: self.limits,
+            peak_memory: 0,
+        };
+        let mut store = w::Store::new(&self.engine, state);
+        store.limiter(|state| state);
+        if self.fuel_metering {
+            store.set_fuel(u64::MAX)?;
+        }
+        store.epoch_deadline_trap();
+        store.set_epoch_deadline(match self.deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                (left.as_millis() / EPOCH_TICK.as_millis()) as u64 + 1
+            }
+            // The store adds the current epoch to this value, keep room for it.
+            None => u64::MAX / 2,
+        });
 
-        log::debug!("resolved={}", externs.len());
+        let instance = linker.instantiate(&mut store, &module)?;
+        log::debug!("resolved={}", module.imports().len());
 
-        /*
-                    let wasi = wasmtime_wasi::create_wasi_instance(
-                        &self.store, self.mounts.as_ref(), self.args.as_ref(), &vec![])?;
-        */
-        let instnace = w::Instance::new(&self.store, &module, externs.as_ref())?;
+        let f = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+        let started = Instant::now();
+        let exit = match f.call(&mut store, ()) {
+            Ok(()) => Exit::Success,
+            Err(e) => into_exit(e),
+        };
+        let wall_time = started.elapsed();
 
-        let f = instnace
-            .find_export_by_name("_start")
-            .unwrap()
-            .func()
-            .unwrap();
-        let _result = f.borrow().call(&[])?;
-        Ok(())
+        // Fuel consumed before an epoch interrupt never reaches the store, so
+        // it is unknown for timeouts.
+        let fuel = if self.fuel_metering && !matches!(exit, Exit::Timeout) {
+            Some(u64::MAX - store.get_fuel()?)
+        } else {
+            None
+        };
+        Ok(RunOutcome {
+            exit,
+            stats: RunStats {
+                fuel,
+                wall_time,
+                peak_memory: Some(store.data().peak_memory as u64),
+            },
+        })
     }
 
     fn for_wasm_path(&self, path: &Path) -> Result<Self::Code> {
-        let data = std::fs::read(path)?;
-        let module = w::Module::new(&self.store, data.as_ref())?;
+        let module = self.modules.load(&self.engine, path)?;
         Ok(WtCode { module })
     }
 }
 
+impl WtBox {
+    /// Maps a guest path onto the host through the innermost mount containing it.
+    fn resolve(&self, guest_path: &str) -> Result<(PathBuf, Mode)> {
+        let guest_path = guest_path.trim_end_matches('/');
+        self.mounts
+            .iter()
+            .filter_map(|(des, src, mode)| {
+                let des = des.trim_end_matches('/');
+                let rest = guest_path.strip_prefix(des)?;
+                if !rest.is_empty() && !rest.starts_with('/') {
+                    return None;
+                }
+                Some((des.len(), src.join(rest.trim_start_matches('/')), *mode))
+            })
+            .max_by_key(|(len, _, _)| *len)
+            .map(|(_, src, mode)| (src, mode))
+            .ok_or_else(|| anyhow::anyhow!("work dir {} is outside of mounts", guest_path))
+    }
+}
+
+fn into_exit(e: anyhow::Error) -> Exit {
+    if let Some(exit) = e.downcast_ref::<I32Exit>() {
+        return match exit.0 {
+            0 => Exit::Success,
+            code => Exit::Code(code),
+        };
+    }
+    if let Some(w::Trap::Interrupt) = e.downcast_ref::<w::Trap>() {
+        return Exit::Timeout;
+    }
+    Exit::Trap {
+        message: e.root_cause().to_string(),
+        backtrace: e
+            .downcast_ref::<w::WasmBacktrace>()
+            .map(ToString::to_string),
+    }
+}
+
+#[inline]
+fn into_perms(mode: Mode) -> (DirPerms, FilePerms) {
+    match mode {
+        Mode::Ro => (DirPerms::READ, FilePerms::READ),
+        Mode::Rw => (DirPerms::all(), FilePerms::all()),
+        // Opening anything in a preopen requires `DirPerms::READ`, so write-only
+        // is left to files. WASI preview1 reads do not check `FilePerms::READ`
+        // though, so guests can still read files of write-only mounts.
+        Mode::Wo => (DirPerms::all(), FilePerms::WRITE),
+    }
+}
+
 pub struct WtCode {
     module: w::Module,
 }
+
+struct State {
+    wasi: WasiP1Ctx,
+    limits: ResourceLimits,
+    peak_memory: usize,
+}
+
+impl w::ResourceLimiter for State {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        if let Some(max_memory) = self.limits.max_memory {
+            if desired > max_memory {
+                log::warn!("memory limit exceeded: {} > {}", desired, max_memory);
+                return Ok(false);
+            }
+        }
+        self.peak_memory = self.peak_memory.max(desired);
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> Result<bool> {
+        match self.limits.max_table_elements {
+            Some(max_elements) => Ok(desired <= max_elements),
+            None => Ok(true),
+        }
+    }
+
+    fn instances(&self) -> usize {
+        self.limits
+            .max_instances
+            .unwrap_or(w::DEFAULT_INSTANCE_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gwr-runtime-wasmtime-test_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs a guest given in the text format from `dir`, after `setup` configured its sandbox.
+    fn run_wat(
+        dir: &Path,
+        wat: &str,
+        limits: &ResourceLimits,
+        setup: impl FnOnce(&mut WtBox),
+    ) -> RunOutcome {
+        run_wat_with(&EngineConfig::default(), dir, wat, limits, setup)
+    }
+
+    fn run_wat_with(
+        config: &EngineConfig,
+        dir: &Path,
+        wat: &str,
+        limits: &ResourceLimits,
+        setup: impl FnOnce(&mut WtBox),
+    ) -> RunOutcome {
+        let wasm_path = dir.join("main.wat");
+        fs::write(&wasm_path, wat).unwrap();
+        let engine = engine(config).unwrap();
+        let mut sandbox = engine.sandbox(Vec::new(), limits).unwrap();
+        setup(&mut sandbox);
+        let code = sandbox.for_wasm_path(&wasm_path).unwrap();
+        sandbox.run(code).unwrap()
+    }
+
+    /// `path_open` flag creating missing files.
+    const O_CREAT: u32 = 1;
+
+    /// Guest opening `path` in preopen `fd` and reading or writing a byte,
+    /// exiting with the errno of the first failing call.
+    fn access_wat(fd: u32, path: &str, oflags: u32, write: bool) -> String {
+        let (io, rights) = if write {
+            ("fd_write", 1 << 6)
+        } else {
+            ("fd_read", 1 << 1)
+        };
+        format!(
+            r#"(module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "{io}"
+                    (func $io (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 32) "\40\00\00\00\01\00\00\00")
+                (data (i32.const 64) "x")
+                (data (i32.const 128) "{path}")
+                (func (export "_start") (local $errno i32)
+                    (local.set $errno (call $open (i32.const {fd}) (i32.const 0)
+                        (i32.const 128) (i32.const {len}) (i32.const {oflags})
+                        (i64.const {rights}) (i64.const {rights}) (i32.const 0) (i32.const 0)))
+                    (if (local.get $errno) (then (call $exit (local.get $errno))))
+                    (call $exit (call $io (i32.load (i32.const 0))
+                        (i32.const 32) (i32.const 1) (i32.const 8)))))"#,
+            io = io,
+            fd = fd,
+            path = path,
+            len = path.len(),
+            oflags = oflags,
+            rights = rights,
+        )
+    }
+
+    #[test]
+    fn test_mount_modes() {
+        let dir = test_dir("mount_modes");
+        fs::write(dir.join("in.txt"), "input").unwrap();
+        let access = |name: &str, mode, oflags, write| {
+            let wat = access_wat(3, name, oflags, write);
+            run_wat(&dir, &wat, &ResourceLimits::default(), |sandbox| {
+                sandbox.mount(&dir, "/data", mode).unwrap()
+            })
+            .exit
+            .is_success()
+        };
+
+        assert!(access("in.txt", Mode::Ro, 0, false));
+        assert!(!access("in.txt", Mode::Ro, 0, true));
+        assert!(!access("ro.txt", Mode::Ro, O_CREAT, true));
+        assert!(!dir.join("ro.txt").exists());
+
+        assert!(access("in.txt", Mode::Rw, 0, false));
+        assert!(access("rw.txt", Mode::Rw, O_CREAT, true));
+        assert_eq!(fs::read_to_string(dir.join("rw.txt")).unwrap(), "x");
+
+        assert!(access("wo.txt", Mode::Wo, O_CREAT, true));
+        assert_eq!(fs::read_to_string(dir.join("wo.txt")).unwrap(), "x");
+        // See `into_perms`, reads are not denied.
+        assert!(access("in.txt", Mode::Wo, 0, false));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_work_dir() {
+        let dir = test_dir("work_dir");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        // The "." preopen follows the mounts.
+        let wat = access_wat(4, "out.txt", O_CREAT, true);
+        let outcome = run_wat(&dir, &wat, &ResourceLimits::default(), |sandbox| {
+            sandbox.mount(&dir, "/data", Mode::Rw).unwrap();
+            sandbox.work_dir("/data/sub/").unwrap();
+        });
+        assert!(outcome.exit.is_success(), "{:?}", outcome.exit);
+        assert_eq!(fs::read_to_string(dir.join("sub/out.txt")).unwrap(), "x");
+
+        let engine = engine(&EngineConfig::default()).unwrap();
+        let mut sandbox = engine
+            .sandbox(Vec::new(), &ResourceLimits::default())
+            .unwrap();
+        sandbox.mount(&dir, "/data", Mode::Rw).unwrap();
+        assert!(sandbox.work_dir("data").is_err());
+        sandbox.work_dir("/database").unwrap();
+        let code = sandbox.for_wasm_path(&dir.join("main.wat")).unwrap();
+        assert!(sandbox.run(code).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exit() {
+        let dir = test_dir("exit");
+        let run = |body: &str| {
+            let wat = format!(
+                r#"(module
+                    (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+                    (memory (export "memory") 1)
+                    (func (export "_start") {}))"#,
+                body
+            );
+            run_wat(&dir, &wat, &ResourceLimits::default(), |_| ()).exit
+        };
+
+        assert!(matches!(run(""), Exit::Success));
+        assert!(matches!(run("(call $exit (i32.const 0))"), Exit::Success));
+        assert!(matches!(run("(call $exit (i32.const 3))"), Exit::Code(3)));
+        match run("unreachable") {
+            Exit::Trap { message, backtrace } => {
+                assert!(message.contains("unreachable"), "{}", message);
+                assert!(backtrace.is_some());
+            }
+            exit => panic!("unexpected exit: {:?}", exit),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let dir = test_dir("memory_limit");
+        // Exits with 1 when growing the memory from 1 to 5 pages fails.
+        let wat = r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+            (memory (export "memory") 1)
+            (func (export "_start")
+                (if (i32.eq (memory.grow (i32.const 4)) (i32.const -1))
+                    (then (call $exit (i32.const 1))))))"#;
+        let page = 64 * 1024;
+
+        let outcome = run_wat(&dir, wat, &ResourceLimits::default(), |_| ());
+        assert!(outcome.exit.is_success(), "{:?}", outcome.exit);
+        assert_eq!(outcome.stats.peak_memory, Some(5 * page as u64));
+
+        let limits = ResourceLimits {
+            max_memory: Some(2 * page),
+            ..ResourceLimits::default()
+        };
+        let outcome = run_wat(&dir, wat, &limits, |_| ());
+        assert!(matches!(outcome.exit, Exit::Code(1)), "{:?}", outcome.exit);
+        assert_eq!(outcome.stats.peak_memory, Some(page as u64));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_table_and_instance_limits() {
+        let dir = test_dir("table_and_instance_limits");
+        // Exits with 1 when growing the table from 1 to 11 elements fails.
+        let wat = r#"(module
+            (import "wasi_snapshot_preview1" "proc_exit" (func $exit (param i32)))
+            (memory (export "memory") 1)
+            (table 1 funcref)
+            (func (export "_start")
+                (if (i32.eq (table.grow (ref.null func) (i32.const 10)) (i32.const -1))
+                    (then (call $exit (i32.const 1))))))"#;
+
+        let outcome = run_wat(&dir, wat, &ResourceLimits::default(), |_| ());
+        assert!(outcome.exit.is_success(), "{:?}", outcome.exit);
+
+        let limits = ResourceLimits {
+            max_table_elements: Some(5),
+            ..ResourceLimits::default()
+        };
+        let outcome = run_wat(&dir, wat, &limits, |_| ());
+        assert!(matches!(outcome.exit, Exit::Code(1)), "{:?}", outcome.exit);
+
+        let engine = engine(&EngineConfig::default()).unwrap();
+        let limits = ResourceLimits {
+            max_instances: Some(0),
+            ..ResourceLimits::default()
+        };
+        let sandbox = engine.sandbox(Vec::new(), &limits).unwrap();
+        let code = sandbox.for_wasm_path(&dir.join("main.wat")).unwrap();
+        assert!(sandbox.run(code).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_timeout() {
+        let dir = test_dir("timeout");
+        let wat = r#"(module (func (export "_start") (loop (br 0))))"#;
+        let outcome = run_wat(&dir, wat, &ResourceLimits::default(), |sandbox| {
+            sandbox
+                .deadline(Instant::now() + Duration::from_millis(300))
+                .unwrap()
+        });
+        assert!(matches!(outcome.exit, Exit::Timeout), "{:?}", outcome.exit);
+        assert!(outcome.stats.wall_time >= Duration::from_millis(200));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fuel() {
+        let dir = test_dir("fuel");
+        let config = EngineConfig {
+            fuel_metering: true,
+            ..EngineConfig::default()
+        };
+        // Counts down from 1000 to 0.
+        let wat = r#"(module (func (export "_start") (local $i i32)
+            (local.set $i (i32.const 1000))
+            (loop (br_if 0 (local.tee $i (i32.sub (local.get $i) (i32.const 1)))))))"#;
+        let outcome = run_wat_with(&config, &dir, wat, &ResourceLimits::default(), |_| ());
+        assert!(outcome.exit.is_success(), "{:?}", outcome.exit);
+        let fuel = outcome.stats.fuel.unwrap();
+        assert!(fuel >= 1000, "{}", fuel);
+
+        let wat = r#"(module (func (export "_start") (loop (br 0))))"#;
+        let outcome = run_wat_with(&config, &dir, wat, &ResourceLimits::default(), |sandbox| {
+            sandbox
+                .deadline(Instant::now() + Duration::from_millis(300))
+                .unwrap()
+        });
+        assert!(matches!(outcome.exit, Exit::Timeout), "{:?}", outcome.exit);
+        assert_eq!(outcome.stats.fuel, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
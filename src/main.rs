@@ -1,5 +1,8 @@
 #![allow(clippy::unit_arg)]
-use gwr_backend::{rt::Engine, Flags};
+use gwr_backend::{
+    rt::{Engine, EngineConfig},
+    Flags,
+};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use structopt::*;
@@ -16,6 +19,9 @@ struct Opt {
     /// Runtime type to use. (spwasm, wasmtime)
     #[structopt(long, short)]
     runtime: Option<RuntimeName>,
+    /// Only run split locally and check its output, without running subtasks.
+    #[structopt(long = "validate-tasks")]
+    validate_tasks: bool,
     /// Wasm App binary path to run.
     #[structopt(parse(from_os_str))]
     pub wasm_app: PathBuf,
@@ -24,29 +30,30 @@ struct Opt {
 }
 
 #[cfg(all(feature = "spwasm", feature = "wasmtime"))]
-fn default_runtime(wasm_app: &Path) -> anyhow::Result<Runtime> {
+fn default_runtime(wasm_app: &Path, config: &EngineConfig) -> anyhow::Result<Runtime> {
     if wasm_app.with_extension("js").exists() {
-        return RuntimeName::SpWasm.into_runtime();
+        return RuntimeName::SpWasm.into_runtime(config);
     }
-    RuntimeName::Wasmtime.into_runtime()
+    RuntimeName::Wasmtime.into_runtime(config)
 }
 
 #[cfg(all(not(feature = "spwasm"), feature = "wasmtime"))]
-fn default_runtime(_: &Path) -> anyhow::Result<Runtime> {
-    RuntimeName::Wasmtime.into_runtime()
+fn default_runtime(_: &Path, config: &EngineConfig) -> anyhow::Result<Runtime> {
+    RuntimeName::Wasmtime.into_runtime(config)
 }
 
 #[cfg(all(feature = "spwasm", not(feature = "wasmtime")))]
-fn default_runtime(_: &Path) -> anyhow::Result<Runtime> {
-    RuntimeName::SpWasm.into_runtime()
+fn default_runtime(_: &Path, config: &EngineConfig) -> anyhow::Result<Runtime> {
+    RuntimeName::SpWasm.into_runtime(config)
 }
 
 impl Opt {
     fn runtime(&self) -> anyhow::Result<Runtime> {
+        let config = self.flags.engine_config();
         if let Some(runtime_name) = &self.runtime {
-            runtime_name.clone().into_runtime()
+            runtime_name.clone().into_runtime(&config)
         } else {
-            default_runtime(&self.wasm_app)
+            default_runtime(&self.wasm_app, &config)
         }
     }
 }
@@ -108,10 +115,10 @@ macro_rules! gen {
 
         impl RuntimeName {
 
-            fn into_runtime(self) -> anyhow::Result<Runtime> {
+            fn into_runtime(self, config: &EngineConfig) -> anyhow::Result<Runtime> {
                Ok(match self { $(
                 $(#[cfg(feature=$feature)])?
-                RuntimeName::$id => Runtime::$id(<$engine>::new()?)
+                RuntimeName::$id => Runtime::$id(<$engine>::new(config)?)
                 ),*
                })
             }
@@ -146,6 +153,12 @@ macro_rules! gen {
 
             fn run(self) -> anyhow::Result<()> {
                 let runtime = self.runtime()?;
+                if self.validate_tasks {
+                    return internal_gen_run! {
+                        on(runtime)
+                        engine => gwr_backend::validate_tasks(engine, &self.flags, &self.wasm_app, self.wasm_app_args.as_ref())
+                    };
+                }
                 Ok(match self.backend {
                 $(
                     $(#[cfg(feature=$b_feature)])?